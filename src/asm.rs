@@ -2,44 +2,252 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 // Copyright (C) 2025 Michael Büsch <m@bues.ch>
 
+use crate::{
+    avr_deviceinfo::AvrDeviceInfoDesc,
+    devices::device_arch,
+    ihex::to_ihex,
+    layout::Layout,
+    opcodes::{EncodeEnv, encode_insn},
+    program::{Insn, Program},
+};
 use anyhow::{self as ah, Context as _, format_err as err};
-use std::{path::Path, process::Stdio};
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    process::Stdio,
+};
 use tempfile::tempdir;
 use tokio::{fs::OpenOptions, io::AsyncWriteExt as _, process::Command};
 
-pub async fn assemble_hex(asm_text: &str, out_hex: &Path) -> ah::Result<()> {
-    let temp_dir = tempdir().context("Create temporary directory")?;
-
-    let mut in_asm = temp_dir.path().to_path_buf();
-    in_asm.push("in.asm");
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Built-in encoder.
+    Native,
+    /// The avra assembler.
+    Avra,
+    /// GNU avr-as and avr-ld.
+    Gnu,
+}
 
-    let mut in_asm_file = OpenOptions::new()
+async fn write_tmp_file(path: &Path, content: &str) -> ah::Result<()> {
+    OpenOptions::new()
         .create(true)
         .truncate(true)
         .write(true)
-        .open(&in_asm)
+        .open(path)
         .await
-        .context("Open tmp asm file")?;
-
-    in_asm_file
-        .write_all(asm_text.as_bytes())
+        .context("Open tmp file")?
+        .write_all(content.as_bytes())
         .await
-        .context("Write tmp asm file")?;
+        .context("Write tmp file")?;
+    Ok(())
+}
 
-    let mut proc = Command::new("avra")
-        .arg("-o")
-        .arg(out_hex)
-        .arg(in_asm)
+async fn run_tool<I, S>(tool: &str, args: I) -> ah::Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut proc = Command::new(tool)
+        .args(args)
         .stdin(Stdio::null())
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
         .spawn()
-        .context("avra")?;
-    if !proc.wait().await.context("Await avra execution")?.success() {
-        return Err(err!("avra failed"));
+        .context(tool.to_string())?;
+    if !proc
+        .wait()
+        .await
+        .with_context(|| format!("Await {tool} execution"))?
+        .success()
+    {
+        return Err(err!("{tool} failed"));
     }
+    Ok(())
+}
+
+pub async fn assemble_avra(asm_text: &str, out_hex: &Path) -> ah::Result<()> {
+    let temp_dir = tempdir().context("Create temporary directory")?;
+
+    let mut in_asm = temp_dir.path().to_path_buf();
+    in_asm.push("in.asm");
+    write_tmp_file(&in_asm, asm_text).await?;
+
+    run_tool(
+        "avra",
+        [OsStr::new("-o"), out_hex.as_os_str(), in_asm.as_os_str()],
+    )
+    .await
+}
+
+/// Get the ELF output file name of the GNU backend.
+pub fn gnu_elf_path(out_hex: &Path, out_elf: Option<&Path>) -> PathBuf {
+    match out_elf {
+        Some(out_elf) => out_elf.to_path_buf(),
+        None => out_hex.with_extension("elf"),
+    }
+}
+
+/// Command line of avr-as.
+fn gnu_as_args(arch: &str, in_asm: &Path, obj: &Path) -> Vec<OsString> {
+    vec![
+        format!("-mmcu={arch}").into(),
+        "-o".into(),
+        obj.into(),
+        in_asm.into(),
+    ]
+}
+
+/// Command line of avr-ld.
+/// The program starts at the global text section label at address 0.
+fn gnu_ld_args(arch: &str, obj: &Path, out_elf: &Path) -> Vec<OsString> {
+    vec![
+        "-m".into(),
+        arch.into(),
+        "-e".into(),
+        "____section_text__".into(),
+        "-o".into(),
+        out_elf.into(),
+        obj.into(),
+    ]
+}
+
+pub async fn assemble_gnu(
+    asm_text: &str,
+    device: &AvrDeviceInfoDesc,
+    out_hex: &Path,
+    out_elf: &Path,
+) -> ah::Result<()> {
+    let temp_dir = tempdir().context("Create temporary directory")?;
+    let arch = device_arch(device);
 
+    let mut in_asm = temp_dir.path().to_path_buf();
+    in_asm.push("in.s");
+    write_tmp_file(&in_asm, asm_text).await?;
+
+    let mut obj = temp_dir.path().to_path_buf();
+    obj.push("in.o");
+
+    run_tool("avr-as", gnu_as_args(arch, &in_asm, &obj)).await?;
+    run_tool("avr-ld", gnu_ld_args(arch, &obj, out_elf)).await?;
+
+    run_tool(
+        "avr-objcopy",
+        [
+            OsStr::new("-j"),
+            OsStr::new(".text"),
+            OsStr::new("-O"),
+            OsStr::new("ihex"),
+            out_elf.as_os_str(),
+            out_hex.as_os_str(),
+        ],
+    )
+    .await
+}
+
+/// Lay out the program and encode it into a flash image.
+fn encode_program(program: &Program) -> ah::Result<Vec<u8>> {
+    let Some(device) = program.device() else {
+        return Err(err!("No device info."));
+    };
+    let Some(text) = program.section_text() else {
+        return Err(err!("No .text section."));
+    };
+
+    // Assign word addresses to all labels and instructions.
+    let layout = Layout::new(program)?;
+    let mut insns: Vec<(u32, &Insn)> = vec![];
+    let mut addr = 0;
+    for part in text.final_parts() {
+        for insn in part.final_insns() {
+            insns.push((addr, insn));
+            addr += insn.size_words();
+        }
+    }
+
+    // Encode the instructions.
+    let resolve = |label: &str| layout.resolve(label);
+    let env = EncodeEnv {
+        flash_size: device.flash_size,
+        resolve: &resolve,
+    };
+    let mut image = Vec::with_capacity(layout.text_words() as usize * 2);
+    for (addr, insn) in insns {
+        let code = encode_insn(insn.name(), insn.ops(), addr, &env)
+            .with_context(|| format!("Encode '{insn}' at 0x{:X}", addr * 2))?;
+        for word in code {
+            image.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+    if let Some(data) = program.section_data() {
+        image.extend_from_slice(data.data());
+        if image.len() % 2 != 0 {
+            image.push(0);
+        }
+    }
+
+    if image.len() > device.flash_size as usize {
+        return Err(err!(
+            "Program size {} bytes exceeds the flash size {} bytes.",
+            image.len(),
+            device.flash_size
+        ));
+    }
+    Ok(image)
+}
+
+pub async fn assemble_native(program: &Program, out_hex: &Path) -> ah::Result<()> {
+    let image = encode_program(program)?;
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(out_hex)
+        .await
+        .context("Open output hex file")?
+        .write_all(to_ihex(&image).as_bytes())
+        .await
+        .context("Write output hex file")?;
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::program;
+
+    #[test]
+    fn test_gnu_asm() {
+        let program = program(
+            "atmega328p",
+            "__vectors:\n    rjmp main\nmain:\n    rjmp main\n",
+        );
+        let asm = program.to_gnu_asm().unwrap();
+        assert_eq!(
+            asm,
+            "    .section .text\n    .global ____section_text__\n____section_text__:\n\
+             __vectors:\n    rjmp main\nmain:\n    rjmp main\n"
+        );
+    }
+
+    #[test]
+    fn test_gnu_args() {
+        let args = gnu_as_args("avr5", Path::new("in.s"), Path::new("in.o"));
+        assert_eq!(args, ["-mmcu=avr5", "-o", "in.o", "in.s"]);
+        let args = gnu_ld_args("avr5", Path::new("in.o"), Path::new("out.elf"));
+        assert_eq!(
+            args,
+            [
+                "-m",
+                "avr5",
+                "-e",
+                "____section_text__",
+                "-o",
+                "out.elf",
+                "in.o"
+            ]
+        );
+    }
+}
+
 // vim: ts=4 sw=4 expandtab
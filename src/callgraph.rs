@@ -0,0 +1,525 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+//! Whole-program call graph between the parts of the text section.
+
+use crate::{
+    cfg::Cfg,
+    dasm::{imm_pairs, is_flash_read, pair_reaches_z},
+    insninfo::Flow,
+    operand::Operand,
+    program::{Insn, Program},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+};
+
+/// Name of the interrupt vector table part.
+pub const VECTORS: &str = "__vectors";
+
+/// Kind of a reference from one part to another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RefKind {
+    /// rcall, call
+    Call,
+    /// rjmp, jmp or conditional branch into another part.
+    Jump,
+    /// Execution runs off the end of the part into the next part.
+    FallThrough,
+    /// The flash address of the target is loaded into a register (e.g. ldi with pm()).
+    Address,
+    /// An immediate value loaded into a register pair equals the flash address of the target.
+    /// Unlike `Address` this is a plain number that doesn't follow the target when it moves.
+    Immediate,
+    /// A data section word equals the flash word address of the target.
+    Data,
+    /// icall, eicall, ijmp, eijmp with unknown target.
+    Indirect,
+}
+
+impl RefKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RefKind::Call => "call",
+            RefKind::Jump => "jump",
+            RefKind::FallThrough => "fall-through",
+            RefKind::Address => "address",
+            RefKind::Immediate => "immediate",
+            RefKind::Data => "data",
+            RefKind::Indirect => "indirect",
+        }
+    }
+
+    /// The reference makes the target address observable,
+    /// so that the target may be reached through an indirect call or jump.
+    pub fn takes_address(&self) -> bool {
+        matches!(self, RefKind::Address | RefKind::Immediate | RefKind::Data)
+    }
+}
+
+/// Reference from a part to another part.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CallEdge {
+    /// Index of the referencing part, or `None` for references from data.
+    pub from: Option<usize>,
+    /// Index of the referenced part, or `None` if unknown.
+    pub to: Option<usize>,
+    pub kind: RefKind,
+}
+
+/// Call graph over the parts of the text section, with all patches applied.
+/// Parts are identified by their index in `CodeSection::parts`.
+#[derive(Clone, Debug)]
+pub struct CallGraph {
+    edges: Vec<CallEdge>,
+    roots: Vec<usize>,
+    reachable: Vec<bool>,
+    count: usize,
+}
+
+impl CallGraph {
+    pub fn new(program: &Program) -> Self {
+        let Some(text) = program.section_text() else {
+            return Self {
+                edges: vec![],
+                roots: vec![],
+                reachable: vec![],
+                count: 0,
+            };
+        };
+        let parts = text.parts();
+
+        // Map all labels to their part.
+        let mut labels = HashMap::new();
+        for (i, part) in parts.iter().enumerate() {
+            let part = part.final_part();
+            labels.insert(part.name().to_string(), i);
+            for insn in part.final_insns() {
+                if let Some(label) = insn.label() {
+                    labels.insert(label.to_string(), i);
+                }
+            }
+        }
+
+        // Map the flash word addresses of the parts to the parts.
+        let mut part_addrs = HashMap::new();
+        for (i, part) in parts.iter().enumerate() {
+            if let Some(insn) = part.insns().first() {
+                part_addrs.insert(insn.addr() / 2, i);
+            }
+        }
+
+        let mut edges = vec![];
+        fn add_edge(edges: &mut Vec<CallEdge>, edge: CallEdge) {
+            if !edges.contains(&edge) {
+                edges.push(edge);
+            }
+        }
+
+        for (i, part) in parts.iter().enumerate() {
+            let part = part.final_part();
+            let insns: Vec<Insn> = part.final_insns().cloned().collect();
+            if insns.is_empty() {
+                continue;
+            }
+            for insn in &insns {
+                // Data words in the text section, e.g. jump tables.
+                if insn.name() == ".word"
+                    && let Some(&to) = insn.ops()[0]
+                        .imm()
+                        .and_then(|w| part_addrs.get(&(w as u32)))
+                {
+                    add_edge(
+                        &mut edges,
+                        CallEdge {
+                            from: None,
+                            to: Some(to),
+                            kind: RefKind::Data,
+                        },
+                    );
+                    continue;
+                }
+                let kind = match insn.flow() {
+                    Flow::Call => RefKind::Call,
+                    Flow::Jump | Flow::Branch => RefKind::Jump,
+                    Flow::IndirectCall | Flow::IndirectJump => {
+                        add_edge(
+                            &mut edges,
+                            CallEdge {
+                                from: Some(i),
+                                to: None,
+                                kind: RefKind::Indirect,
+                            },
+                        );
+                        continue;
+                    }
+                    _ => RefKind::Address,
+                };
+                for op in insn.ops() {
+                    let label = match op {
+                        Operand::Label(label) if kind != RefKind::Address => label,
+                        Operand::Expr(expr) => &expr.label,
+                        _ => continue,
+                    };
+                    let Some(&to) = labels.get(label) else {
+                        continue;
+                    };
+                    // Only an address reference to itself is relevant.
+                    if to != i || kind == RefKind::Address {
+                        add_edge(
+                            &mut edges,
+                            CallEdge {
+                                from: Some(i),
+                                to: Some(to),
+                                kind,
+                            },
+                        );
+                    }
+                }
+            }
+            for to in immediate_addrs(&insns).filter_map(|a| part_addrs.get(&a)) {
+                add_edge(
+                    &mut edges,
+                    CallEdge {
+                        from: Some(i),
+                        to: Some(*to),
+                        kind: RefKind::Immediate,
+                    },
+                );
+            }
+            // A call to a function that doesn't return never continues behind it,
+            // unless the call can be skipped.
+            let skipped = insns.len() >= 2 && insns[insns.len() - 2].flow() == Flow::Skip;
+            let noreturn_call = !skipped
+                && insns.last().is_some_and(|insn| {
+                    insn.flow() == Flow::Call
+                        && insn
+                            .ops()
+                            .last()
+                            .and_then(|op| op.label())
+                            .and_then(|label| labels.get(label))
+                            .is_some_and(|&to| parts[to].final_part().is_noreturn())
+                });
+            if !noreturn_call
+                && Cfg::from_insns(part.name(), &insns).falls_through_end()
+                && let Some(next) =
+                    (i + 1..parts.len()).find(|&n| !parts[n].final_part().insns().is_empty())
+            {
+                add_edge(
+                    &mut edges,
+                    CallEdge {
+                        from: Some(i),
+                        to: Some(next),
+                        kind: RefKind::FallThrough,
+                    },
+                );
+            }
+        }
+
+        // Words in the data section that look like function pointers.
+        if let Some(data) = program.section_data() {
+            for word in data_words(data.data()) {
+                if let Some(&to) = part_addrs.get(&word) {
+                    add_edge(
+                        &mut edges,
+                        CallEdge {
+                            from: None,
+                            to: Some(to),
+                            kind: RefKind::Data,
+                        },
+                    );
+                }
+            }
+        }
+
+        let roots: Vec<usize> = parts
+            .iter()
+            .position(|p| p.name() == VECTORS)
+            .or((!parts.is_empty()).then_some(0))
+            .into_iter()
+            .collect();
+
+        let mut graph = Self {
+            edges,
+            roots,
+            reachable: vec![],
+            count: parts.len(),
+        };
+        // Parts with an address referenced from data may be reached indirectly.
+        let start: Vec<usize> = graph
+            .edges
+            .iter()
+            .filter(|e| e.from.is_none())
+            .filter_map(|e| e.to)
+            .chain(graph.roots.iter().copied())
+            .collect();
+        graph.reachable = graph.reachable_from(start);
+        graph
+    }
+
+    /// Reachability of each part from the given start parts, by part index.
+    pub fn reachable_from(&self, start: impl IntoIterator<Item = usize>) -> Vec<bool> {
+        let mut reached = vec![false; self.count];
+        let mut work: Vec<usize> = start.into_iter().collect();
+        while let Some(p) = work.pop() {
+            if reached[p] {
+                continue;
+            }
+            reached[p] = true;
+            work.extend(self.edges_from(p).filter_map(|e| e.to));
+        }
+        reached
+    }
+
+    /// Indices of the parts where execution starts (the vector table).
+    pub fn roots(&self) -> &[usize] {
+        &self.roots
+    }
+
+    pub fn edges(&self) -> &[CallEdge] {
+        &self.edges
+    }
+
+    /// Outgoing references of a part.
+    pub fn edges_from(&self, part: usize) -> impl Iterator<Item = &CallEdge> {
+        self.edges.iter().filter(move |e| e.from == Some(part))
+    }
+
+    /// Incoming references of a part.
+    pub fn edges_to(&self, part: usize) -> impl Iterator<Item = &CallEdge> {
+        self.edges.iter().filter(move |e| e.to == Some(part))
+    }
+
+    /// The program contains indirect calls or jumps.
+    #[allow(unused)]
+    pub fn has_indirect(&self) -> bool {
+        self.edges.iter().any(|e| e.kind == RefKind::Indirect)
+    }
+
+    /// Indices of the parts whose address is observable.
+    pub fn address_taken(&self) -> impl Iterator<Item = usize> + '_ {
+        self.edges
+            .iter()
+            .filter(|e| e.kind.takes_address())
+            .filter_map(|e| e.to)
+    }
+
+    /// The part may be executed, starting from the roots.
+    /// Parts with observable addresses are conservatively considered
+    /// reachable from the referencing part.
+    pub fn is_reachable(&self, part: usize) -> bool {
+        self.reachable.get(part).copied().unwrap_or(false)
+    }
+
+    /// Human readable call graph.
+    pub fn report(&self, program: &Program) -> String {
+        let Some(text) = program.section_text() else {
+            return String::new();
+        };
+        let name = |index: Option<usize>| match index {
+            Some(i) => text.part_at(i).name().to_string(),
+            None => "?".to_string(),
+        };
+        let mut s = String::new();
+        writeln!(s, "Call graph:").unwrap();
+        for (i, part) in text.parts().iter().enumerate() {
+            if part.final_part().insns().is_empty() {
+                continue;
+            }
+            let mark = if self.is_reachable(i) {
+                ""
+            } else {
+                " (unreachable)"
+            };
+            if part.name() == part.demangled() {
+                writeln!(s, "  {}{mark}", part.name()).unwrap();
+            } else {
+                writeln!(s, "  {}{mark} ; {}", part.name(), part.demangled()).unwrap();
+            }
+            for edge in self.edges_to(i).filter(|e| e.from.is_none()) {
+                writeln!(s, "    <- {}", edge.kind.name()).unwrap();
+            }
+            for edge in self.edges_from(i) {
+                writeln!(s, "    {} -> {}", edge.kind.name(), name(edge.to)).unwrap();
+            }
+        }
+        s
+    }
+}
+
+/// Flash word addresses that might be composed from immediate register loads
+/// (`ldi` or `subi`/`sbci` with the negated value) into a register pair.
+/// Byte addresses of data that is read from flash through Z
+/// are converted to word addresses.
+fn immediate_addrs(insns: &[Insn]) -> impl Iterator<Item = u32> {
+    let mut addrs = vec![];
+    for pair in imm_pairs(insns) {
+        let value = pair.value as u32;
+        addrs.push(value);
+        if value.is_multiple_of(2) && pair_reaches_z(insns, &pair, is_flash_read) {
+            addrs.push(value / 2);
+        }
+    }
+    addrs.into_iter()
+}
+
+/// Little endian data words. Pointers in the data section are 16 bit aligned.
+fn data_words(data: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    data.chunks_exact(2)
+        .map(|w| u16::from_le_bytes([w[0], w[1]]) as u32)
+}
+
+/// All plain numbers of the program that might be flash word addresses:
+/// Data section words, `.word` data in the text section and register pair immediates.
+pub fn number_addrs(program: &Program) -> HashSet<u32> {
+    let mut addrs = HashSet::new();
+    if let Some(text) = program.section_text() {
+        for part in text.final_parts() {
+            let insns: Vec<Insn> = part.final_insns().cloned().collect();
+            addrs.extend(
+                insns
+                    .iter()
+                    .filter(|insn| insn.name() == ".word")
+                    .filter_map(|insn| insn.ops()[0].imm())
+                    .map(|w| w as u32),
+            );
+            addrs.extend(immediate_addrs(&insns));
+        }
+    }
+    if let Some(data) = program.section_data() {
+        addrs.extend(data_words(data.data()));
+    }
+    addrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{program, program_with_data};
+
+    /// Edges as (from, kind, to) part names.
+    fn edges(program: &Program) -> Vec<(String, &'static str, String)> {
+        let text = program.section_text().unwrap();
+        let name = |i: Option<usize>| match i {
+            Some(i) => text.part_at(i).name().to_string(),
+            None => "?".to_string(),
+        };
+        CallGraph::new(program)
+            .edges()
+            .iter()
+            .map(|e| (name(e.from), e.kind.name(), name(e.to)))
+            .collect()
+    }
+
+    fn edge(from: &str, kind: &'static str, to: &str) -> (String, &'static str, String) {
+        (from.to_string(), kind, to.to_string())
+    }
+
+    #[test]
+    fn test_edges() {
+        let program = program(
+            "atmega328p",
+            "
+__vectors:
+    jmp main
+main:
+    rcall f
+    ldi r30, low(g)
+    ldi r31, high(g)
+    icall
+    breq main
+    cpi r24, 0x01
+f:
+    rjmp g
+g:
+    ret
+h:
+    ret
+",
+        );
+        assert_eq!(
+            edges(&program),
+            [
+                edge("__vectors", "jump", "main"),
+                edge("main", "call", "f"),
+                edge("main", "address", "g"),
+                edge("main", "indirect", "?"),
+                edge("main", "fall-through", "f"),
+                edge("f", "jump", "g"),
+            ]
+        );
+        let graph = CallGraph::new(&program);
+        assert!(graph.is_reachable(3));
+        assert!(!graph.is_reachable(4));
+    }
+
+    const ASM: &str = "
+__vectors:
+    jmp main
+__table:
+    nop
+main:
+";
+
+    fn immediate_targets(main: &str) -> Vec<String> {
+        let program = program("atmega328p", &format!("{ASM}{main}    ret\n"));
+        edges(&program)
+            .into_iter()
+            .filter(|e| e.1 == "immediate")
+            .map(|e| e.2)
+            .collect()
+    }
+
+    #[test]
+    fn test_immediate() {
+        // Word address in a register pair.
+        assert_eq!(
+            immediate_targets("    ldi r24, 0x02\n    ldi r25, 0x00\n"),
+            ["__table"]
+        );
+        // Byte address read through Z.
+        assert_eq!(
+            immediate_targets("    ldi r30, 0x04\n    ldi r31, 0x00\n    lpm r24, Z\n"),
+            ["__table"]
+        );
+        assert_eq!(
+            immediate_targets(
+                "    ldi r30, 0x04\n    ldi r31, 0x00\n    add r30, r24\n    l: elpm r24, Z+\n"
+            ),
+            ["__table"]
+        );
+        // Not read from flash, so it's no byte address of code.
+        assert!(immediate_targets("    ldi r24, 0x04\n    ldi r25, 0x00\n").is_empty());
+        assert!(
+            immediate_targets("    ldi r30, 0x04\n    ldi r31, 0x00\n    ld r24, Z\n").is_empty()
+        );
+        // Unrelated loads of the two bytes.
+        assert!(
+            immediate_targets("    ldi r24, 0x02\n    rcall __table\n    ldi r25, 0x00\n")
+                .is_empty()
+        );
+        assert!(
+            immediate_targets("    ldi r24, 0x02\n    ldi r25, 0x01\n    ldi r25, 0x00\n")
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_data() {
+        let data_edges = |data: &[u8]| {
+            let program = program_with_data("atmega328p", &format!("{ASM}    ret\n"), data);
+            edges(&program)
+                .into_iter()
+                .filter(|e| e.1 == "data")
+                .map(|e| e.2)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(data_edges(&[0x02, 0x00]), ["__table"]);
+        // Only aligned words.
+        assert!(data_edges(&[0xAA, 0x02, 0x00, 0xBB]).is_empty());
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -0,0 +1,357 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+//! Basic-block control flow graph of a single `Part`.
+
+use crate::{
+    insninfo::Flow,
+    program::{Insn, Part},
+};
+use std::collections::HashMap;
+
+/// Kind of a control flow edge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EdgeKind {
+    /// Execution continues with the next instruction.
+    FallThrough,
+    /// Taken conditional branch.
+    Branch,
+    /// Taken skip over the next instruction.
+    Skip,
+    /// Unconditional jump.
+    Jump,
+}
+
+/// Destination of a control flow edge.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum EdgeTarget {
+    /// Basic block within the same part.
+    Block(usize),
+    /// Label outside of the part.
+    Label(String),
+    /// The start of the part following this part in flash.
+    NextPart,
+    /// Computed jump target (ijmp, eijmp).
+    Unknown,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    pub target: EdgeTarget,
+}
+
+/// Sequence of instructions with a single entry and a single exit.
+#[derive(Clone, Debug)]
+pub struct BasicBlock {
+    /// Index of the first instruction in the part.
+    start: usize,
+    /// Index after the last instruction in the part.
+    end: usize,
+    succs: Vec<Edge>,
+    preds: Vec<usize>,
+}
+
+impl BasicBlock {
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    #[allow(unused)]
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// Instruction indices of this block.
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.start..self.end
+    }
+
+    /// Index of the last instruction.
+    #[allow(unused)]
+    pub fn last(&self) -> usize {
+        self.end - 1
+    }
+
+    pub fn succs(&self) -> &[Edge] {
+        &self.succs
+    }
+
+    /// Indices of the blocks within the part that have an edge into this block.
+    pub fn preds(&self) -> &[usize] {
+        &self.preds
+    }
+}
+
+/// Control flow graph of a part.
+/// Calls are not block terminators. They return to the next instruction.
+#[derive(Clone, Debug)]
+pub struct Cfg {
+    blocks: Vec<BasicBlock>,
+    /// Instruction index to block index.
+    insn_block: Vec<usize>,
+}
+
+impl Cfg {
+    /// Build the CFG of the unpatched instructions of a part.
+    pub fn new(part: &Part) -> Self {
+        Self::from_insns(part.name(), part.insns())
+    }
+
+    /// Build the CFG of an instruction sequence.
+    /// The first instruction is labeled `part_name`.
+    pub fn from_insns(part_name: &str, insns: &[Insn]) -> Self {
+        let count = insns.len();
+
+        let mut labels = HashMap::new();
+        if count > 0 {
+            labels.insert(part_name, 0);
+        }
+        for (i, insn) in insns.iter().enumerate() {
+            if let Some(label) = insn.label() {
+                labels.insert(label, i);
+            }
+        }
+
+        // Find the block leaders.
+        let mut leader = vec![false; count + 1];
+        if count > 0 {
+            leader[0] = true;
+        }
+        for (i, insn) in insns.iter().enumerate() {
+            if insn.label().is_some() {
+                leader[i] = true;
+            }
+            match insn.flow() {
+                Flow::Next | Flow::Call | Flow::IndirectCall => (),
+                Flow::Skip => {
+                    leader[i + 1] = true;
+                    leader[(i + 2).min(count)] = true;
+                }
+                Flow::Branch | Flow::Jump | Flow::IndirectJump | Flow::Return => {
+                    leader[i + 1] = true;
+                }
+            }
+            if let Some(&target) = branch_label(insn).and_then(|l| labels.get(l)) {
+                leader[target] = true;
+            }
+        }
+
+        let mut blocks = vec![];
+        let mut insn_block = vec![0; count];
+        for i in 0..count {
+            if leader[i] {
+                blocks.push(BasicBlock {
+                    start: i,
+                    end: i,
+                    succs: vec![],
+                    preds: vec![],
+                });
+            }
+            let block = blocks.len() - 1;
+            blocks[block].end = i + 1;
+            insn_block[i] = block;
+        }
+
+        // Connect the blocks.
+        let target_at = |index: usize| {
+            if index < count {
+                EdgeTarget::Block(insn_block[index])
+            } else {
+                EdgeTarget::NextPart
+            }
+        };
+        let target_label = |insn: &Insn| match branch_label(insn) {
+            Some(label) => match labels.get(label) {
+                Some(&index) => EdgeTarget::Block(insn_block[index]),
+                None => EdgeTarget::Label(label.to_string()),
+            },
+            None => EdgeTarget::Unknown,
+        };
+        for block in &mut blocks {
+            let last = block.end - 1;
+            let insn = &insns[last];
+            let fall = Edge {
+                kind: EdgeKind::FallThrough,
+                target: target_at(last + 1),
+            };
+            block.succs = match insn.flow() {
+                Flow::Next | Flow::Call | Flow::IndirectCall => vec![fall],
+                Flow::Branch => vec![
+                    fall,
+                    Edge {
+                        kind: EdgeKind::Branch,
+                        target: target_label(insn),
+                    },
+                ],
+                Flow::Skip => vec![
+                    fall,
+                    Edge {
+                        kind: EdgeKind::Skip,
+                        target: target_at((last + 2).min(count)),
+                    },
+                ],
+                Flow::Jump => vec![Edge {
+                    kind: EdgeKind::Jump,
+                    target: target_label(insn),
+                }],
+                Flow::IndirectJump => vec![Edge {
+                    kind: EdgeKind::Jump,
+                    target: EdgeTarget::Unknown,
+                }],
+                Flow::Return => vec![],
+            };
+        }
+        for b in 0..blocks.len() {
+            for e in 0..blocks[b].succs.len() {
+                if let EdgeTarget::Block(succ) = blocks[b].succs[e].target
+                    && !blocks[succ].preds.contains(&b)
+                {
+                    blocks[succ].preds.push(b);
+                }
+            }
+        }
+
+        Self { blocks, insn_block }
+    }
+
+    pub fn blocks(&self) -> &[BasicBlock] {
+        &self.blocks
+    }
+
+    pub fn block(&self, index: usize) -> &BasicBlock {
+        &self.blocks[index]
+    }
+
+    /// The entry block, or `None` for an empty part.
+    pub fn entry(&self) -> Option<&BasicBlock> {
+        self.blocks.first()
+    }
+
+    /// Index of the block containing the instruction at `insn_index`.
+    pub fn block_of(&self, insn_index: usize) -> usize {
+        self.insn_block[insn_index]
+    }
+
+    /// Reachability of each block from the entry block, by block index.
+    #[allow(unused)]
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reached = vec![false; self.blocks.len()];
+        let mut work = vec![];
+        if !self.blocks.is_empty() {
+            work.push(0);
+        }
+        while let Some(b) = work.pop() {
+            if reached[b] {
+                continue;
+            }
+            reached[b] = true;
+            for edge in &self.blocks[b].succs {
+                if let EdgeTarget::Block(succ) = edge.target {
+                    work.push(succ);
+                }
+            }
+        }
+        reached
+    }
+
+    /// Execution may run off the end of the part into the next part.
+    pub fn falls_through_end(&self) -> bool {
+        self.blocks
+            .iter()
+            .flat_map(|b| &b.succs)
+            .any(|e| e.target == EdgeTarget::NextPart)
+    }
+}
+
+/// Get the flash label operand of a branch or jump instruction.
+fn branch_label(insn: &Insn) -> Option<&str> {
+    match insn.flow() {
+        Flow::Branch | Flow::Jump => insn.ops().last().and_then(|op| op.label()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::program;
+
+    fn cfg(asm: &str) -> Cfg {
+        let program = program("atmega328p", asm);
+        let text = program.section_text().unwrap();
+        Cfg::new(text.parts().first().unwrap())
+    }
+
+    fn ranges(cfg: &Cfg) -> Vec<std::ops::Range<usize>> {
+        cfg.blocks().iter().map(|b| b.range()).collect()
+    }
+
+    #[test]
+    fn test_branch() {
+        let cfg = cfg("
+f:
+    cpi r24, 0x01
+    brne done
+    ldi r24, 0x02
+    done: ret
+");
+        assert_eq!(ranges(&cfg), [0..2, 2..3, 3..4]);
+        assert_eq!(
+            cfg.block(0).succs(),
+            [
+                Edge {
+                    kind: EdgeKind::FallThrough,
+                    target: EdgeTarget::Block(1),
+                },
+                Edge {
+                    kind: EdgeKind::Branch,
+                    target: EdgeTarget::Block(2),
+                },
+            ]
+        );
+        assert_eq!(cfg.block(2).preds(), [0, 1]);
+        assert!(cfg.block(2).succs().is_empty());
+        assert!(!cfg.falls_through_end());
+    }
+
+    #[test]
+    fn test_skip() {
+        let cfg = cfg("
+f:
+    sbrc r24, 0
+    call g
+    inc r24
+    ret
+");
+        // The skipped instruction is a block of its own.
+        assert_eq!(ranges(&cfg), [0..1, 1..2, 2..4]);
+        assert_eq!(cfg.block(0).succs()[1].kind, EdgeKind::Skip);
+        assert_eq!(cfg.block(0).succs()[1].target, EdgeTarget::Block(2));
+        assert_eq!(cfg.block_of(3), 2);
+    }
+
+    #[test]
+    fn test_leave_part() {
+        let cfg = cfg("
+f:
+    cpi r24, 0x00
+    breq g
+    ijmp
+    rjmp f
+    inc r24
+");
+        assert_eq!(ranges(&cfg), [0..2, 2..3, 3..4, 4..5]);
+        assert_eq!(
+            cfg.block(0).succs()[1].target,
+            EdgeTarget::Label("g".to_string())
+        );
+        assert_eq!(cfg.block(1).succs()[0].target, EdgeTarget::Unknown);
+        assert_eq!(cfg.block(2).succs()[0].target, EdgeTarget::Block(0));
+        assert_eq!(cfg.reachable(), [true, true, false, false]);
+        assert!(cfg.falls_through_end());
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -4,20 +4,47 @@
 
 use crate::{
     avr_deviceinfo::{AvrElfBytes, elf_avr_deviceinfo},
+    insninfo::Flow,
+    opcodes::{decode_insn, parse_insn_ops},
+    operand::{Expr, ExprFunc, Operand},
     program::{CodeSection, DataSection, Insn, Part, Program},
 };
 use anyhow::{self as ah, Context as _, format_err as err};
+use elf::abi::{STB_GLOBAL, STB_WEAK, STT_FILE, STT_FUNC, STT_SECTION};
 use regex::Regex;
 use rustc_demangle::demangle;
-use std::{collections::HashMap, path::Path, process::Stdio};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    process::Stdio,
+};
 use tokio::process::Command;
 
-async fn resolve_references(program: &mut Program) -> ah::Result<()> {
+/// Get the label of the instruction at `target` (part index, insn index).
+/// A new label is created, if the instruction does not have one.
+fn target_label(program: &mut Program, target: (usize, usize), rel_target: &mut u32) -> String {
+    let text = program.section_text_mut().unwrap();
+    if target.1 == 0 {
+        return text.part_at(target.0).name().to_string();
+    }
+    let insn = text.part_at_mut(target.0).insn_at_mut(target.1);
+    match insn.label() {
+        Some(label) => label.to_string(),
+        None => {
+            let label = format!("__reltgt{:04X}", *rel_target);
+            *rel_target += 1;
+            insn.set_label(Some(label.to_string()));
+            label
+        }
+    }
+}
+
+async fn resolve_references(program: &mut Program, rel_target: &mut u32) -> ah::Result<()> {
     let Some(device) = program.device() else {
         return Err(err!("No device info"));
     };
     let flash_size = device.flash_size;
-    let flash_mask: u16 = (flash_size - 1).try_into().context("Gen flash mask")?;
+    let flash_mask: u32 = flash_size - 1;
 
     let mut addr_map = HashMap::with_capacity(flash_size.try_into()?);
     if let Some(text) = program.section_text() {
@@ -28,10 +55,6 @@ async fn resolve_references(program: &mut Program) -> ah::Result<()> {
         }
     }
 
-    let re_reloffs = Regex::new(r"^\.([\+-]\d+)$").unwrap();
-
-    let mut rel_target = 0_u32;
-
     for p in 0..program.section_text().map(|t| t.parts().len()).unwrap_or(0) {
         for i in 0..program
             .section_text()
@@ -45,60 +68,235 @@ async fn resolve_references(program: &mut Program) -> ah::Result<()> {
                 .insn_at(i)
                 .clone();
             for (iop, op) in insn.ops().iter().enumerate() {
-                if let Some(cap) = re_reloffs.captures(op) {
-                    let offs = cap.get(1).unwrap().as_str();
-                    let Ok(offs) = offs.parse::<i32>() else {
-                        return Err(err!("Relative offset '{offs}' is not i32."));
-                    };
-
-                    let abs = (insn.addr() as i32 + 2 + offs) as u16;
-                    let abs = abs & flash_mask;
-
-                    let Some(target) = addr_map.get(&abs) else {
-                        return Err(err!("Relative offset '{offs}' target not found."));
-                    };
-
-                    let target_label = if target.1 == 0 {
-                        program
-                            .section_text()
-                            .unwrap()
-                            .part_at(target.0)
-                            .name()
-                            .to_string()
-                    } else {
-                        match program
-                            .section_text()
-                            .unwrap()
-                            .part_at(target.0)
-                            .insn_at(target.1)
-                            .label()
-                        {
-                            Some(label) => label.to_string(),
-                            None => {
-                                let label = format!("__reltgt{rel_target:04X}");
-                                rel_target += 1;
-                                program
-                                    .section_text_mut()
-                                    .unwrap()
-                                    .part_at_mut(target.0)
-                                    .insn_at_mut(target.1)
-                                    .set_label(Some(label.to_string()));
-                                label
-                            }
-                        }
-                    };
-
-                    program
-                        .section_text_mut()
-                        .unwrap()
-                        .part_at_mut(p)
-                        .insn_at_mut(i)
-                        .set_op(iop, target_label);
-                }
+                let target = match op {
+                    Operand::RelOffset(offs) => {
+                        let abs = (insn.addr() as i64 + 2 + *offs as i64) as u32;
+                        let abs = abs & flash_mask;
+
+                        let Some(target) = addr_map.get(&abs) else {
+                            return Err(err!(
+                                "'{insn}' at 0x{:X}: Relative offset '{offs}' target not found.",
+                                insn.addr()
+                            ));
+                        };
+                        *target
+                    }
+                    Operand::FlashAddr(abs) => {
+                        let Some(target) = addr_map.get(abs) else {
+                            return Err(err!(
+                                "'{insn}' at 0x{:X}: Absolute target '{op}' not found.",
+                                insn.addr()
+                            ));
+                        };
+                        *target
+                    }
+                    _ => continue,
+                };
+
+                let target_label = target_label(program, target, rel_target);
+
+                program
+                    .section_text_mut()
+                    .unwrap()
+                    .part_at_mut(p)
+                    .insn_at_mut(i)
+                    .set_op(iop, Operand::Label(target_label));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Immediate load of one byte of a register pair.
+/// Returns the register, the byte and whether it is subtracted (subi/sbci).
+fn imm_load(insn: &Insn) -> Option<(u8, u8, bool)> {
+    let neg = match insn.name() {
+        "ldi" => false,
+        "subi" | "sbci" => true,
+        _ => return None,
+    };
+    let reg = insn.ops().first()?.reg()?;
+    let imm = insn.ops().get(1)?.imm()?;
+    Some((reg, imm as u8, neg))
+}
+
+/// Find the load of the other byte of the register pair loaded at `insns[i]`.
+/// Both loads must be in the same straight line code.
+fn imm_load_partner(insns: &[Insn], i: usize) -> Option<usize> {
+    let (reg, _, neg) = imm_load(&insns[i])?;
+    let other = reg ^ 1;
+    // subi must be first, because sbci uses its carry.
+    let partner_name = match (neg, reg % 2) {
+        (false, _) => "ldi",
+        (true, 0) if insns[i].name() == "subi" => "sbci",
+        (true, _) => return None,
+    };
+    for (j, insn) in insns.iter().enumerate().skip(i + 1).take(3) {
+        if insn.label().is_some() {
+            break;
+        }
+        if insn.name() == partner_name && insn.ops()[0].reg() == Some(other) {
+            return Some(j);
+        }
+        let defs = insn.reg_defs();
+        if insn.flow() != Flow::Next || defs.contains(reg) || defs.contains(other) {
+            break;
+        }
+    }
+    None
+}
+
+/// Register pair loaded with a 16 bit immediate by two instructions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImmPair {
+    /// Index of the load of the low byte.
+    pub lo: usize,
+    /// Index of the load of the high byte.
+    pub hi: usize,
+    /// Low register of the pair.
+    pub reg: u8,
+    /// The loaded value. For subi/sbci the value that is added to the pair.
+    pub value: u16,
+    /// The value is subtracted (subi/sbci) with the negated immediate.
+    pub neg: bool,
+}
+
+impl ImmPair {
+    /// Index behind the last of the two loads.
+    fn end(&self) -> usize {
+        self.lo.max(self.hi) + 1
+    }
+}
+
+/// Find the immediate loads of register pairs.
+/// X and Y can't address flash, so pairs loaded into them are skipped.
+pub fn imm_pairs(insns: &[Insn]) -> Vec<ImmPair> {
+    let mut pairs = vec![];
+    let mut paired = vec![false; insns.len()];
+    for i in 0..insns.len() {
+        let Some(j) = imm_load_partner(insns, i).filter(|&j| !paired[i] && !paired[j]) else {
+            continue;
+        };
+        paired[i] = true;
+        paired[j] = true;
+        let (reg_i, byte_i, neg) = imm_load(&insns[i]).unwrap();
+        let (_, byte_j, _) = imm_load(&insns[j]).unwrap();
+        let (lo, hi, reg) = if reg_i % 2 == 0 {
+            ((i, byte_i), (j, byte_j), reg_i)
+        } else {
+            ((j, byte_j), (i, byte_i), reg_i - 1)
+        };
+        if matches!(reg, 26 | 28) {
+            continue;
+        }
+        let raw = u16::from_le_bytes([lo.1, hi.1]);
+        pairs.push(ImmPair {
+            lo: lo.0,
+            hi: hi.0,
+            reg,
+            value: if neg { raw.wrapping_neg() } else { raw },
+            neg,
+        });
+    }
+    pairs
+}
+
+/// Check whether the value loaded by `pair` reaches Z at an instruction
+/// that satisfies `pred`, in the straight line code behind the loads.
+/// The value may be copied with movw and an offset may be added to it.
+pub fn pair_reaches_z(insns: &[Insn], pair: &ImmPair, pred: impl Fn(&Insn) -> bool) -> bool {
+    // Low registers of the pairs that hold the value.
+    let mut held = vec![pair.reg];
+    for insn in &insns[pair.end()..] {
+        if held.contains(&30) && pred(insn) {
+            return true;
+        }
+        let dst = insn.ops().first();
+        let dst = dst.and_then(|op| op.reg().or(op.reg_pair()));
+        let copy = match insn.name() {
+            "movw" => insn.ops()[1].reg_pair().filter(|src| held.contains(src)),
+            _ => None,
+        };
+        let offset = matches!(
+            insn.name(),
+            "add" | "adc" | "adiw" | "sub" | "sbc" | "subi" | "sbci" | "sbiw"
+        ) && dst.is_some_and(|d| held.contains(&(d & !1)));
+        if !offset {
+            let defs = insn.reg_defs();
+            held.retain(|&r| !defs.contains(r) && !defs.contains(r + 1));
+        }
+        if let (Some(_), Some(dst)) = (copy, dst) {
+            held.push(dst);
+        }
+        if held.is_empty() || insn.flow() != Flow::Next {
+            return false;
+        }
+    }
+    false
+}
+
+/// The instruction reads flash through Z.
+/// The libgcc table jump helpers read the jump table with lpm/elpm.
+pub fn is_flash_read(insn: &Insn) -> bool {
+    matches!(insn.name(), "lpm" | "elpm")
+        || (matches!(insn.flow(), Flow::Call | Flow::Jump)
+            && insn.ops()[0]
+                .label()
+                .is_some_and(|l| l.starts_with("__tablejump")))
+}
+
+/// Convert immediate loads of code addresses into pm() label expressions,
+/// so that they follow the code when it moves.
+///
+/// Only register pair loads that provably are code addresses are converted:
+/// The value must reach Z at an indirect jump or call in the same part.
+/// All other numbers that look like code addresses, including `.word` data,
+/// remain plain numbers. The call graph finds them as references
+/// that pin their target.
+pub fn relocate_code_addrs(program: &mut Program, rel_target: &mut u32) -> ah::Result<()> {
+    let Some(text) = program.section_text() else {
+        return Ok(());
+    };
+
+    // Map the word addresses to the parts and instructions.
+    let mut insn_addrs = HashMap::new();
+    for (p, part) in text.parts().iter().enumerate() {
+        for (i, insn) in part.insns().iter().enumerate() {
+            insn_addrs.insert(insn.addr() / 2, (p, i));
+        }
+    }
+
+    // (part, instruction, operand value function, negated, target)
+    let mut relocs = vec![];
+    for (p, part) in text.parts().iter().enumerate() {
+        let insns = part.insns();
+        for pair in imm_pairs(insns) {
+            let indirect =
+                |insn: &Insn| matches!(insn.flow(), Flow::IndirectJump | Flow::IndirectCall);
+            if let Some(&target) = insn_addrs.get(&(pair.value as u32))
+                && pair_reaches_z(insns, &pair, indirect)
+            {
+                relocs.push((p, pair.lo, ExprFunc::Lo8, pair.neg, target));
+                relocs.push((p, pair.hi, ExprFunc::Hi8, pair.neg, target));
             }
         }
     }
 
+    for (p, i, func, neg, target) in relocs {
+        let label = target_label(program, target, rel_target);
+        let expr = Expr::new(func, &label, true);
+        let expr = if neg { expr.negated() } else { expr };
+        let insn = program
+            .section_text_mut()
+            .unwrap()
+            .part_at_mut(p)
+            .insn_at_mut(i);
+        let last = insn.ops().len() - 1;
+        insn.set_op(last, Operand::Expr(expr));
+        insn.check()
+            .with_context(|| format!("Relocate '{insn}' at 0x{:X}", insn.addr()))?;
+    }
     Ok(())
 }
 
@@ -129,12 +327,18 @@ async fn process_dasm(program: &mut Program, raw: &str) -> ah::Result<()> {
             let name = cap.get(3).unwrap().as_str().trim();
             let opers = cap.get(4).unwrap().as_str().trim();
 
-            let Ok(addr_int) = u16::from_str_radix(addr, 16) else {
+            let Ok(addr_int) = u32::from_str_radix(addr, 16) else {
                 return Err(err!(
                     "Failed to parse address '{addr}' of '{name} {opers}'."
                 ));
             };
-            let opers_list = opers.split(',').map(|o| o.trim().to_string()).collect();
+            let opers_list: Vec<&str> = if opers.is_empty() {
+                vec![]
+            } else {
+                opers.split(',').map(|o| o.trim()).collect()
+            };
+            let opers_list = parse_insn_ops(name, &opers_list)
+                .with_context(|| format!("Parse instruction at addr {addr}"))?;
 
             let insn = Insn::new(name, opers_list, None, addr_int);
             if let Some(sect) = program.section_text_mut() {
@@ -182,12 +386,10 @@ async fn process_dasm(program: &mut Program, raw: &str) -> ah::Result<()> {
         }
     }
 
-    resolve_references(program).await?;
-
     Ok(())
 }
 
-pub async fn disassemble_elf_text(program: &mut Program, file: &Path) -> ah::Result<()> {
+async fn objdump_elf_text(program: &mut Program, file: &Path) -> ah::Result<()> {
     let proc = Command::new("avr-objdump")
         .arg("--disassemble")
         .arg("--disassemble-zeroes")
@@ -211,6 +413,211 @@ pub async fn disassemble_elf_text(program: &mut Program, file: &Path) -> ah::Res
     Ok(())
 }
 
+/// Symbols that are preferred as part name, if several symbols share an address.
+/// Most preferred first.
+const PREFERRED_SYMBOLS: &[&str] = &[
+    "__vectors",
+    "__ctors_end",
+    "__ctors_start",
+    "__trampolines_start",
+    "__do_copy_data",
+    "__do_clear_bss",
+    "__do_global_ctors",
+    "__bad_interrupt",
+    "main",
+    "_exit",
+];
+
+/// Get the `.text` symbols that start a new part.
+/// Returns a map of section offset to symbol name.
+fn text_part_symbols(
+    elf: &AvrElfBytes<'_>,
+    text_index: usize,
+    text_addr: u64,
+    text_size: u64,
+) -> ah::Result<BTreeMap<u64, String>> {
+    let Some((symtab, strtab)) = elf.symbol_table().context("Parse symbol table")? else {
+        return Err(err!("ELF file does not have a symbol table."));
+    };
+
+    let rank = |name: &str, symtype: u8, bind: u8| {
+        let preferred = PREFERRED_SYMBOLS
+            .iter()
+            .position(|p| *p == name)
+            .unwrap_or(PREFERRED_SYMBOLS.len());
+        let bind = match bind {
+            STB_GLOBAL => 0,
+            STB_WEAK => 1,
+            _ => 2,
+        };
+        (preferred, symtype != STT_FUNC, bind)
+    };
+
+    let mut symbols: BTreeMap<u64, (String, (usize, bool, i32))> = BTreeMap::new();
+    for sym in symtab.iter() {
+        if sym.st_shndx as usize != text_index
+            || sym.st_symtype() == STT_SECTION
+            || sym.st_symtype() == STT_FILE
+        {
+            continue;
+        }
+        let name = strtab
+            .get(sym.st_name as usize)
+            .context("Get symbol name")?;
+        if name.is_empty() || name.starts_with(".L") {
+            continue;
+        }
+        if sym.st_value < text_addr || sym.st_value - text_addr >= text_size {
+            continue;
+        }
+        let offset = sym.st_value - text_addr;
+        let sym_rank = rank(name, sym.st_symtype(), sym.st_bind());
+        match symbols.get(&offset) {
+            Some((cur_name, cur_rank)) if (cur_rank, cur_name.as_str()) <= (&sym_rank, name) => (),
+            _ => {
+                symbols.insert(offset, (name.to_string(), sym_rank));
+            }
+        }
+    }
+
+    Ok(symbols
+        .into_iter()
+        .map(|(offset, (name, _))| (offset, name))
+        .collect())
+}
+
+/// Decode the `.text` section of the ELF file with the built-in instruction decoder.
+fn decode_elf_text(program: &mut Program, elf: &AvrElfBytes<'_>) -> ah::Result<()> {
+    let (shdrs, strtab) = elf
+        .section_headers_with_strtab()
+        .context("Parse section table")?;
+    let (Some(shdrs), Some(strtab)) = (shdrs, strtab) else {
+        return Err(err!("ELF file does not have a section table."));
+    };
+    let Some((text_index, shdr)) = shdrs
+        .iter()
+        .enumerate()
+        .find(|(_, shdr)| strtab.get(shdr.sh_name as usize).ok() == Some(".text"))
+    else {
+        return Err(err!("ELF file does not have a .text section."));
+    };
+    let (text, _) = elf
+        .section_data(&shdr)
+        .context("Get .text section content")?;
+    if text.len() % 2 != 0 {
+        return Err(err!("The .text section size is not a multiple of 2."));
+    }
+    let words: Vec<u16> = text
+        .chunks_exact(2)
+        .map(|w| u16::from_le_bytes([w[0], w[1]]))
+        .collect();
+
+    let symbols = text_part_symbols(elf, text_index, shdr.sh_addr, text.len() as u64)?;
+
+    let mut sect = CodeSection::new(".text");
+    let mut i = 0;
+    while i < words.len() {
+        let offset = i as u64 * 2;
+        if let Some(name) = symbols.get(&offset) {
+            let san_name = sanitize_label(name);
+            let demangled = format!("{:#}", demangle(name));
+            sect.add_part(Part::new(&san_name, &demangled));
+        }
+
+        // A two-word instruction must not cross a symbol boundary.
+        let may_be_long = !symbols.contains_key(&(offset + 2));
+        let dec = decode_insn(&words[i..], may_be_long);
+
+        let addr: u32 = (shdr.sh_addr + offset)
+            .try_into()
+            .context("Instruction address out of range")?;
+        let Some(part) = sect.cur_part_mut() else {
+            return Err(err!(
+                "Got '{}' instruction at addr {addr:X}, \
+                but we are not in a function.",
+                dec.name
+            ));
+        };
+        part.add_insn(Insn::new(&dec.name, dec.ops, None, addr));
+
+        i += dec.size_words as usize;
+    }
+
+    program.set_section_text(Some(sect));
+    Ok(())
+}
+
+/// Compare the built-in disassembly against the avr-objdump disassembly.
+async fn objdump_cross_check(program: &Program, file: &Path) -> ah::Result<()> {
+    let mut objdump_program = Program::new();
+    objdump_elf_text(&mut objdump_program, file).await?;
+
+    let flatten = |program: &Program| -> Vec<(String, String)> {
+        program
+            .section_text()
+            .map(|text| {
+                text.parts()
+                    .iter()
+                    .flat_map(|part| {
+                        part.insns()
+                            .iter()
+                            .map(|insn| (part.name().to_string(), format!("{insn}")))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let native = flatten(program);
+    let objdump = flatten(&objdump_program);
+
+    let mut mismatches = 0;
+    for (i, (n, o)) in native.iter().zip(objdump.iter()).enumerate() {
+        if n != o {
+            eprintln!(
+                "WARNING: Disassembly mismatch at instruction {i}: \
+                built-in '{}: {}' vs. avr-objdump '{}: {}'",
+                n.0, n.1, o.0, o.1
+            );
+            mismatches += 1;
+        }
+    }
+    if native.len() != objdump.len() {
+        return Err(err!(
+            "avr-objdump cross-check: Instruction count mismatch: \
+            built-in {} vs. avr-objdump {}",
+            native.len(),
+            objdump.len()
+        ));
+    }
+    if mismatches > 0 {
+        return Err(err!(
+            "avr-objdump cross-check: {mismatches} instructions differ."
+        ));
+    }
+    Ok(())
+}
+
+pub async fn disassemble_elf_text(
+    program: &mut Program,
+    file: &Path,
+    objdump_check: bool,
+) -> ah::Result<()> {
+    let data = std::fs::read(file).context("Read ELF input file")?;
+    let elf = AvrElfBytes::minimal_parse(&data).context("Parse ELF input file")?;
+    decode_elf_text(program, &elf)?;
+
+    if objdump_check {
+        objdump_cross_check(program, file)
+            .await
+            .context("Cross-check disassembly with avr-objdump")?;
+    }
+
+    let mut rel_target = 0;
+    resolve_references(program, &mut rel_target).await?;
+    relocate_code_addrs(program, &mut rel_target)?;
+    Ok(())
+}
+
 pub async fn extract_elf_data_section(
     program: &mut Program,
     elf: &AvrElfBytes<'_>,
@@ -243,4 +650,160 @@ pub async fn extract_elf_data(program: &mut Program, file: &Path) -> ah::Result<
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::testutil::{part_asm, program};
+
+    #[test]
+    fn test_relocate_ldi() {
+        let program = program(
+            "atmega328p",
+            "
+__vectors:
+    jmp main
+func:
+    ret
+main:
+    ldi r24, 0x02
+    ldi r25, 0x00
+    rcall func
+    ldi r24, 0x02
+    ldi r25, 0x00
+    movw r30, r24
+    icall
+    ldi r30, 0x02
+    ldi r31, 0x00
+    ldi r24, 0x02
+    ldi r25, 0x00
+    eicall
+    ret
+",
+        );
+        assert_eq!(
+            part_asm(&program, "main"),
+            [
+                // A number that happens to equal the address of func.
+                "ldi r24, 0x02",
+                "ldi r25, 0x00",
+                "rcall func",
+                "ldi r24, low(func)",
+                "ldi r25, high(func)",
+                "movw r30, r24",
+                "icall",
+                "ldi r30, low(func)",
+                "ldi r31, high(func)",
+                "ldi r24, 0x02",
+                "ldi r25, 0x00",
+                "eicall",
+                "ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_relocate_jump_target() {
+        // Computed goto within a part.
+        let program = program(
+            "atmega328p",
+            "
+__vectors:
+    jmp func
+func:
+    ldi r31, 0x00
+    ldi r30, 0x06
+    ijmp
+    ret
+    ldi r24, 0x01
+    ret
+",
+        );
+        assert_eq!(
+            part_asm(&program, "func"),
+            [
+                "ldi r31, high(__reltgt0000)",
+                "ldi r30, low(__reltgt0000)",
+                "ijmp",
+                "ret",
+                "__reltgt0000: ldi r24, 0x01",
+                "ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_relocate_table() {
+        // Jump into a table of rjmp with the index in Z.
+        let program = program(
+            "atmega328p",
+            "
+__vectors:
+    jmp func
+func:
+    movw r30, r24
+    subi r30, 0xFA
+    sbci r31, 0xFF
+    ijmp
+    rjmp a
+    rjmp b
+    a: ret
+    b: ret
+",
+        );
+        assert_eq!(
+            part_asm(&program, "func")[..3],
+            [
+                "movw r30, r24",
+                "subi r30, low(-(__reltgt0000))",
+                "sbci r31, high(-(__reltgt0000))",
+            ]
+        );
+        assert_eq!(part_asm(&program, "func")[4], "__reltgt0000: rjmp a");
+    }
+
+    #[test]
+    fn test_relocate_not_code() {
+        let program = program(
+            "atmega328p",
+            "
+__vectors:
+    jmp main
+func:
+    ret
+main:
+    ldi r30, 0x02
+    ldi r31, 0x00
+    rcall func
+    icall
+    ldi r26, 0x02
+    ldi r27, 0x00
+    ijmp
+    ldi r24, 0x02
+    mov r25, r1
+    ldi r25, 0x00
+    .word 0x0002
+",
+        );
+        assert_eq!(
+            part_asm(&program, "main"),
+            [
+                // Z is clobbered by the call.
+                "ldi r30, 0x02",
+                "ldi r31, 0x00",
+                "rcall func",
+                "icall",
+                // X is not used by ijmp.
+                "ldi r26, 0x02",
+                "ldi r27, 0x00",
+                "ijmp",
+                // No register pair.
+                "ldi r24, 0x02",
+                "mov r25, r1",
+                "ldi r25, 0x00",
+                // Data.
+                ".word 0x02",
+            ]
+        );
+    }
+}
+
 // vim: ts=4 sw=4 expandtab
@@ -0,0 +1,148 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+use crate::avr_deviceinfo::AvrDeviceInfoDesc;
+
+/// Static properties of a known AVR device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceDesc {
+    /// Lower case device name.
+    pub name: &'static str,
+    /// GNU binutils architecture name.
+    pub arch: &'static str,
+    /// Number of interrupt vectors, including the reset vector.
+    pub vectors: u32,
+}
+
+macro_rules! devices {
+    ( $( $name:literal => $arch:literal, $vectors:literal ),* $(,)? ) => {
+        &[ $( DeviceDesc { name: $name, arch: $arch, vectors: $vectors }, )* ]
+    }
+}
+
+static DEVICES: &[DeviceDesc] = devices! {
+    "attiny13" => "avr25", 10,
+    "attiny13a" => "avr25", 10,
+    "attiny24" => "avr25", 17,
+    "attiny24a" => "avr25", 17,
+    "attiny25" => "avr25", 15,
+    "attiny44" => "avr25", 17,
+    "attiny44a" => "avr25", 17,
+    "attiny45" => "avr25", 15,
+    "attiny84" => "avr25", 17,
+    "attiny84a" => "avr25", 17,
+    "attiny85" => "avr25", 15,
+    "attiny261a" => "avr25", 19,
+    "attiny461a" => "avr25", 19,
+    "attiny861a" => "avr25", 19,
+    "attiny2313" => "avr25", 19,
+    "attiny2313a" => "avr25", 21,
+    "attiny4313" => "avr25", 21,
+    "attiny88" => "avr25", 20,
+    "attiny167" => "avr35", 20,
+    "atmega8u2" => "avr35", 29,
+    "atmega16u2" => "avr35", 29,
+    "atmega32u2" => "avr35", 29,
+    "atmega8" => "avr4", 19,
+    "atmega8a" => "avr4", 19,
+    "atmega48" => "avr4", 26,
+    "atmega48a" => "avr4", 26,
+    "atmega48p" => "avr4", 26,
+    "atmega48pa" => "avr4", 26,
+    "atmega88" => "avr4", 26,
+    "atmega88a" => "avr4", 26,
+    "atmega88p" => "avr4", 26,
+    "atmega88pa" => "avr4", 26,
+    "atmega8515" => "avr4", 17,
+    "atmega8535" => "avr4", 21,
+    "atmega16" => "avr5", 21,
+    "atmega16a" => "avr5", 21,
+    "atmega32" => "avr5", 21,
+    "atmega32a" => "avr5", 21,
+    "atmega64" => "avr5", 35,
+    "atmega168" => "avr5", 26,
+    "atmega168a" => "avr5", 26,
+    "atmega168p" => "avr5", 26,
+    "atmega168pa" => "avr5", 26,
+    "atmega328" => "avr5", 26,
+    "atmega328p" => "avr5", 26,
+    "atmega328pb" => "avr5", 45,
+    "atmega164p" => "avr5", 31,
+    "atmega164pa" => "avr5", 31,
+    "atmega324p" => "avr5", 31,
+    "atmega324pa" => "avr5", 31,
+    "atmega644" => "avr5", 28,
+    "atmega644p" => "avr5", 31,
+    "atmega644pa" => "avr5", 31,
+    "atmega640" => "avr5", 57,
+    "atmega16u4" => "avr5", 43,
+    "atmega32u4" => "avr5", 43,
+    "atmega128" => "avr51", 35,
+    "atmega128a" => "avr51", 35,
+    "atmega1280" => "avr51", 57,
+    "atmega1281" => "avr51", 57,
+    "atmega1284" => "avr51", 35,
+    "atmega1284p" => "avr51", 35,
+    "at90usb1286" => "avr51", 38,
+    "at90usb1287" => "avr51", 38,
+    "atmega2560" => "avr6", 57,
+    "atmega2561" => "avr6", 57,
+};
+
+/// Known devices that reset the stack pointer to zero instead of to the end of the SRAM.
+static SP_RESET_ZERO: &[&str] = &[
+    "atmega8",
+    "atmega8a",
+    "atmega8515",
+    "atmega8535",
+    "atmega16",
+    "atmega16a",
+    "atmega32",
+    "atmega32a",
+    "atmega64",
+    "atmega128",
+    "atmega128a",
+];
+
+/// Find the static properties of a device.
+pub fn find_device(device_name: &str) -> Option<&'static DeviceDesc> {
+    let device_name = device_name.to_lowercase();
+    DEVICES.iter().find(|d| d.name == device_name)
+}
+
+/// Get the GNU binutils architecture name of a device.
+/// Unknown devices are guessed from the flash size.
+pub fn device_arch(device: &AvrDeviceInfoDesc) -> &'static str {
+    if let Some(desc) = find_device(&device.device_name) {
+        desc.arch
+    } else if device.flash_size > 128 * 1024 {
+        "avr6"
+    } else if device.flash_size > 64 * 1024 {
+        "avr51"
+    } else if device.flash_size > 8 * 1024 {
+        "avr5"
+    } else {
+        "avr25"
+    }
+}
+
+/// The stack pointer is initialized to the end of the SRAM on reset.
+/// Unknown devices are assumed not to do that.
+pub fn device_sp_reset_is_ramend(device: &AvrDeviceInfoDesc) -> bool {
+    let name = device.device_name.to_lowercase();
+    find_device(&name).is_some() && !SP_RESET_ZERO.contains(&name.as_str())
+}
+
+/// Get the number of interrupt vectors of a device, including the reset vector.
+pub fn device_vector_count(device: &AvrDeviceInfoDesc) -> Option<u32> {
+    find_device(&device.device_name).map(|d| d.vectors)
+}
+
+/// Get the size of one interrupt vector table entry in words.
+/// Devices with more than 8 KiB of flash use 2-word jmp vectors.
+pub fn device_vector_words(device: &AvrDeviceInfoDesc) -> u32 {
+    if device.flash_size > 8 * 1024 { 2 } else { 1 }
+}
+
+// vim: ts=4 sw=4 expandtab
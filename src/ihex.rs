@@ -0,0 +1,70 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+use std::fmt::Write as _;
+
+const BYTES_PER_RECORD: usize = 16;
+
+fn write_record(out: &mut String, addr: u16, rectype: u8, data: &[u8]) {
+    let mut sum = data.len() as u8;
+    sum = sum.wrapping_add((addr >> 8) as u8);
+    sum = sum.wrapping_add(addr as u8);
+    sum = sum.wrapping_add(rectype);
+    write!(out, ":{:02X}{addr:04X}{rectype:02X}", data.len()).unwrap();
+    for b in data {
+        write!(out, "{b:02X}").unwrap();
+        sum = sum.wrapping_add(*b);
+    }
+    writeln!(out, "{:02X}", sum.wrapping_neg()).unwrap();
+}
+
+/// Convert a flash image starting at address 0 to Intel HEX.
+pub fn to_ihex(image: &[u8]) -> String {
+    let mut out = String::new();
+    let mut segment = 0;
+    for (i, chunk) in image.chunks(BYTES_PER_RECORD).enumerate() {
+        let addr = i * BYTES_PER_RECORD;
+        let chunk_segment = addr >> 16;
+        if chunk_segment != segment {
+            // Extended linear address record.
+            write_record(&mut out, 0, 4, &(chunk_segment as u16).to_be_bytes());
+            segment = chunk_segment;
+        }
+        write_record(&mut out, addr as u16, 0, chunk);
+    }
+    write_record(&mut out, 0, 1, &[]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small() {
+        let image: Vec<u8> = (0..18).collect();
+        assert_eq!(
+            to_ihex(&image),
+            ":10000000000102030405060708090A0B0C0D0E0F78\n\
+             :020010001011CD\n\
+             :00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn test_extended_address() {
+        let image = vec![0xFF; 0x20010];
+        let hex = to_ihex(&image);
+        let lines: Vec<&str> = hex.lines().collect();
+        assert_eq!(lines.len(), 0x2001 + 2 + 1);
+        assert_eq!(lines[0x0FFF], ":10FFF000FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF11");
+        assert_eq!(lines[0x1000], ":020000040001F9");
+        assert_eq!(lines[0x1001], ":10000000FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF00");
+        assert_eq!(lines[0x2001], ":020000040002F8");
+        assert_eq!(lines[0x2002], ":10000000FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF00");
+        assert_eq!(lines[0x2003], ":00000001FF");
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
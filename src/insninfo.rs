@@ -0,0 +1,619 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+//! Static per-mnemonic instruction properties.
+//!
+//! This is the common ground for all analyses and optimizations:
+//! Register and SREG effects, control flow kind and timing.
+
+use crate::operand::{Operand, PtrMode};
+
+/// AVR CPU core family, as distinguished by the instruction timing tables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CoreFamily {
+    /// Classic core with 16 bit program counter.
+    Avre,
+    /// Classic core with 22 bit program counter (> 128 KiB flash).
+    Avre22,
+    /// XMEGA core.
+    Xmega,
+    /// AVRxt core (tinyAVR 0/1/2, megaAVR 0, AVR Dx).
+    Xt,
+    /// Reduced tiny core.
+    Rc,
+}
+
+impl CoreFamily {
+    /// Get the core family from the GNU binutils architecture name.
+    pub fn from_arch(arch: &str) -> Self {
+        match arch {
+            "avr6" => CoreFamily::Avre22,
+            "avrxmega3" => CoreFamily::Xt,
+            "avrtiny" => CoreFamily::Rc,
+            a if a.starts_with("avrxmega") => CoreFamily::Xmega,
+            _ => CoreFamily::Avre,
+        }
+    }
+
+    fn pc22(&self) -> bool {
+        matches!(self, CoreFamily::Avre22)
+    }
+}
+
+/// Set of registers r0-r31.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct RegSet(u32);
+
+impl RegSet {
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn all() -> Self {
+        Self(!0)
+    }
+
+    /// Set of the registers `first..=last`.
+    pub const fn range(first: u8, last: u8) -> Self {
+        Self((!0u32 >> (31 - last + first)) << first)
+    }
+
+    pub const fn with(self, reg: u8) -> Self {
+        Self(self.0 | (1 << reg))
+    }
+
+    /// Add a register pair, named by its low register.
+    pub const fn with_pair(self, reg: u8) -> Self {
+        self.with(reg).with(reg + 1)
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    #[allow(unused)]
+    pub fn insert(&mut self, reg: u8) {
+        *self = self.with(reg);
+    }
+
+    pub fn contains(&self, reg: u8) -> bool {
+        reg < 32 && self.0 & (1 << reg) != 0
+    }
+
+    #[allow(unused)]
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..32).filter(|r| self.contains(*r))
+    }
+}
+
+impl std::fmt::Display for RegSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        for (i, reg) in self.iter().enumerate() {
+            let sep = if i == 0 { "" } else { " " };
+            write!(f, "{sep}r{reg}")?;
+        }
+        Ok(())
+    }
+}
+
+/// SREG flag bits.
+pub mod sreg {
+    pub const C: u8 = 1 << 0;
+    pub const Z: u8 = 1 << 1;
+    pub const N: u8 = 1 << 2;
+    pub const V: u8 = 1 << 3;
+    pub const S: u8 = 1 << 4;
+    pub const H: u8 = 1 << 5;
+    pub const T: u8 = 1 << 6;
+    pub const I: u8 = 1 << 7;
+    pub const ALL: u8 = 0xFF;
+
+    /// I/O address of SREG.
+    pub const IO: u8 = 0x3f;
+
+    pub(super) const ZC: u8 = Z | C;
+    pub(super) const SVNZ: u8 = S | V | N | Z;
+    pub(super) const SVNZC: u8 = SVNZ | C;
+    pub(super) const HSVNZC: u8 = H | SVNZC;
+}
+
+/// Control flow kind of an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Flow {
+    /// Execution continues with the next instruction.
+    Next,
+    /// Conditional relative branch.
+    Branch,
+    /// Skips the next instruction, if the condition is true.
+    Skip,
+    /// Unconditional jump.
+    Jump,
+    /// Indirect jump through Z.
+    IndirectJump,
+    /// Subroutine call.
+    Call,
+    /// Indirect subroutine call through Z.
+    IndirectCall,
+    /// Return from subroutine (ret) or interrupt (reti).
+    Return,
+}
+
+/// Timing class of an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Timing {
+    One,
+    Two,
+    Branch,
+    Skip,
+    Rjmp,
+    Jmp,
+    Ijmp,
+    Rcall,
+    Call,
+    Icall,
+    Eicall,
+    Ret,
+    Ld,
+    Ldd,
+    St,
+    Std,
+    Lds,
+    Sts,
+    Push,
+    Pop,
+    Lpm,
+    SbiCbi,
+    Spm,
+    Des,
+    Atomic,
+}
+
+/// How an instruction accesses a register operand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Acc {
+    /// Not a register, or not accessed.
+    N,
+    /// Read.
+    U,
+    /// Written.
+    D,
+    /// Read and written.
+    DU,
+}
+
+/// Static properties of an instruction mnemonic.
+#[derive(Debug)]
+pub struct InsnInfo {
+    name: &'static str,
+    flow: Flow,
+    timing: Timing,
+    ops: &'static [Acc],
+    implicit_defs: RegSet,
+    implicit_uses: RegSet,
+    sreg_defs: u8,
+    sreg_uses: u8,
+    mem_read: bool,
+    mem_write: bool,
+}
+
+impl InsnInfo {
+    #[allow(unused)]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn flow(&self) -> Flow {
+        self.flow
+    }
+
+    /// Flags written by the instruction, independent of its operands.
+    pub fn sreg_defs(&self) -> u8 {
+        self.sreg_defs
+    }
+
+    /// Flags read by the instruction, independent of its operands.
+    pub fn sreg_uses(&self) -> u8 {
+        self.sreg_uses
+    }
+
+    /// The instruction reads data memory or the stack.
+    #[allow(unused)]
+    pub fn mem_read(&self) -> bool {
+        self.mem_read
+    }
+
+    /// The instruction writes data memory or the stack.
+    #[allow(unused)]
+    pub fn mem_write(&self) -> bool {
+        self.mem_write
+    }
+
+    /// Minimum and maximum number of cycles on the given core.
+    /// The maximum applies to taken branches and skips over 2-word instructions.
+    pub fn cycles(&self, family: CoreFamily) -> (u8, u8) {
+        use CoreFamily::*;
+        let c = |n| (n, n);
+        match (self.timing, family) {
+            (Timing::One, _) => c(1),
+            (Timing::Two, _) => c(2),
+            (Timing::Branch, _) => (1, 2),
+            (Timing::Skip, _) => (1, 3),
+            (Timing::Rjmp, _) => c(2),
+            (Timing::Jmp, _) => c(3),
+            (Timing::Ijmp, _) => c(2),
+            (Timing::Rcall, Avre) => c(3),
+            (Timing::Rcall, Avre22 | Rc) => c(4),
+            (Timing::Rcall, Xmega | Xt) => c(2),
+            (Timing::Call, Avre) => c(4),
+            (Timing::Call, Avre22) => c(5),
+            (Timing::Call, _) => c(3),
+            (Timing::Icall, Avre | Rc) => c(3),
+            (Timing::Icall, Avre22) => c(4),
+            (Timing::Icall, Xmega | Xt) => c(2),
+            (Timing::Eicall, Avre | Avre22) => c(4),
+            (Timing::Eicall, _) => c(3),
+            (Timing::Ret, f) if f.pc22() => c(5),
+            (Timing::Ret, Rc) => c(6),
+            (Timing::Ret, _) => c(4),
+            (Timing::Ld, Avre | Avre22 | Xt) => c(2),
+            (Timing::Ld, Xmega | Rc) => (1, 2),
+            (Timing::Ldd, _) => c(2),
+            (Timing::St, Avre | Avre22) => c(2),
+            (Timing::St, _) => (1, 2),
+            (Timing::Std, Avre | Avre22 | Xmega) => c(2),
+            (Timing::Std, _) => c(1),
+            (Timing::Lds, Xt) => c(3),
+            (Timing::Lds, Rc) => c(1),
+            (Timing::Lds, _) => c(2),
+            (Timing::Sts, Rc) => c(1),
+            (Timing::Sts, _) => c(2),
+            (Timing::Push, Avre | Avre22) => c(2),
+            (Timing::Push, _) => c(1),
+            (Timing::Pop, Rc) => c(3),
+            (Timing::Pop, _) => c(2),
+            (Timing::Lpm, _) => c(3),
+            (Timing::SbiCbi, Avre | Avre22) => c(2),
+            (Timing::SbiCbi, _) => c(1),
+            (Timing::Spm, _) => (1, u8::MAX),
+            (Timing::Des, _) => (1, 2),
+            (Timing::Atomic, _) => c(2),
+        }
+    }
+
+    /// Registers written by the instruction with the given operands.
+    pub fn reg_defs(&self, ops: &[Operand]) -> RegSet {
+        let mut set = self.implicit_defs;
+        for (op, acc) in ops.iter().zip(self.ops) {
+            if matches!(acc, Acc::D | Acc::DU) {
+                set = set.union(op_regs(op));
+            }
+            if let Operand::Ptr(ptr, PtrMode::PostInc | PtrMode::PreDec) = op {
+                set = set.with_pair(ptr.lo_reg());
+            }
+        }
+        set
+    }
+
+    /// Registers read by the instruction with the given operands.
+    pub fn reg_uses(&self, ops: &[Operand]) -> RegSet {
+        let mut set = self.implicit_uses;
+        for (op, acc) in ops.iter().zip(self.ops) {
+            if matches!(acc, Acc::U | Acc::DU) || matches!(op, Operand::Ptr(..)) {
+                set = set.union(op_regs(op));
+            }
+        }
+        set
+    }
+}
+
+/// Registers named by a register or pointer operand.
+fn op_regs(op: &Operand) -> RegSet {
+    match op {
+        Operand::Reg(r) => RegSet::empty().with(*r),
+        Operand::RegPair(r) => RegSet::empty().with_pair(*r),
+        Operand::Ptr(ptr, _) => RegSet::empty().with_pair(ptr.lo_reg()),
+        _ => RegSet::empty(),
+    }
+}
+
+/// Registers that are not preserved by a called function.
+pub const CALL_CLOBBERED: RegSet = RegSet::range(18, 27).with_pair(30).with(0);
+/// Registers that may carry arguments into a called function, plus the zero register.
+pub const CALL_ARGS: RegSet = RegSet::range(8, 25).with(1);
+/// Registers that are live at a function return:
+/// Return values, callee-saved registers, the zero register and the frame pointer.
+pub const RET_USES: RegSet = RegSet::range(2, 25).with(1).with_pair(28);
+/// Registers of the Z pointer.
+pub const Z_REGS: RegSet = RegSet::empty().with_pair(30);
+
+const fn info(name: &'static str, flow: Flow, timing: Timing, ops: &'static [Acc]) -> InsnInfo {
+    InsnInfo {
+        name,
+        flow,
+        timing,
+        ops,
+        implicit_defs: RegSet::empty(),
+        implicit_uses: RegSet::empty(),
+        sreg_defs: 0,
+        sreg_uses: 0,
+        mem_read: false,
+        mem_write: false,
+    }
+}
+
+impl InsnInfo {
+    const fn sreg(mut self, defs: u8, uses: u8) -> Self {
+        self.sreg_defs = defs;
+        self.sreg_uses = uses;
+        self
+    }
+
+    const fn implicit(mut self, defs: RegSet, uses: RegSet) -> Self {
+        self.implicit_defs = defs;
+        self.implicit_uses = uses;
+        self
+    }
+
+    const fn mem(mut self, read: bool, write: bool) -> Self {
+        self.mem_read = read;
+        self.mem_write = write;
+        self
+    }
+}
+
+const fn alu(name: &'static str, ops: &'static [Acc], defs: u8, uses: u8) -> InsnInfo {
+    info(name, Flow::Next, Timing::One, ops).sreg(defs, uses)
+}
+
+const fn flag(name: &'static str, defs: u8) -> InsnInfo {
+    alu(name, &[], defs, 0)
+}
+
+const fn branch(name: &'static str, uses: u8) -> InsnInfo {
+    info(name, Flow::Branch, Timing::Branch, &[Acc::N]).sreg(0, uses)
+}
+
+const fn mul(name: &'static str) -> InsnInfo {
+    info(name, Flow::Next, Timing::Two, &[Acc::U, Acc::U])
+        .sreg(sreg::ZC, 0)
+        .implicit(RegSet::empty().with_pair(0), RegSet::empty())
+}
+
+use Acc::{D, DU, N, U};
+use sreg::{ALL, C, H, HSVNZC, I, N as FN, S, SVNZ, SVNZC, T, V, Z, ZC};
+
+#[rustfmt::skip]
+static INSN_INFO: &[InsnInfo] = &[
+    alu("add", &[DU, U], HSVNZC, 0),
+    alu("adc", &[DU, U], HSVNZC, C),
+    info("adiw", Flow::Next, Timing::Two, &[DU, N]).sreg(SVNZC, 0),
+    alu("sub", &[DU, U], HSVNZC, 0),
+    alu("subi", &[DU, N], HSVNZC, 0),
+    alu("sbc", &[DU, U], HSVNZC, ZC),
+    alu("sbci", &[DU, N], HSVNZC, ZC),
+    info("sbiw", Flow::Next, Timing::Two, &[DU, N]).sreg(SVNZC, 0),
+    alu("and", &[DU, U], SVNZ, 0),
+    alu("andi", &[DU, N], SVNZ, 0),
+    alu("or", &[DU, U], SVNZ, 0),
+    alu("ori", &[DU, N], SVNZ, 0),
+    alu("eor", &[DU, U], SVNZ, 0),
+    alu("com", &[DU], SVNZC, 0),
+    alu("neg", &[DU], HSVNZC, 0),
+    alu("inc", &[DU], SVNZ, 0),
+    alu("dec", &[DU], SVNZ, 0),
+    mul("mul"),
+    mul("muls"),
+    mul("mulsu"),
+    mul("fmul"),
+    mul("fmuls"),
+    mul("fmulsu"),
+    info("des", Flow::Next, Timing::Des, &[N])
+        .sreg(0, H)
+        .implicit(RegSet::range(0, 15), RegSet::range(0, 15)),
+    alu("cp", &[U, U], HSVNZC, 0),
+    alu("cpc", &[U, U], HSVNZC, ZC),
+    alu("cpi", &[U, N], HSVNZC, 0),
+    alu("asr", &[DU], SVNZC, 0),
+    alu("lsr", &[DU], SVNZC, 0),
+    alu("ror", &[DU], SVNZC, C),
+    alu("swap", &[DU], 0, 0),
+    alu("bst", &[U, N], T, 0),
+    alu("bld", &[DU, N], 0, T),
+    alu("mov", &[D, U], 0, 0),
+    info("movw", Flow::Next, Timing::One, &[D, U]),
+    alu("ldi", &[D, N], 0, 0),
+    info("rjmp", Flow::Jump, Timing::Rjmp, &[N]),
+    info("jmp", Flow::Jump, Timing::Jmp, &[N]),
+    info("ijmp", Flow::IndirectJump, Timing::Ijmp, &[]).implicit(RegSet::empty(), Z_REGS),
+    info("eijmp", Flow::IndirectJump, Timing::Ijmp, &[]).implicit(RegSet::empty(), Z_REGS),
+    info("rcall", Flow::Call, Timing::Rcall, &[N])
+        .sreg(ALL, ALL).implicit(CALL_CLOBBERED, CALL_ARGS).mem(true, true),
+    info("call", Flow::Call, Timing::Call, &[N])
+        .sreg(ALL, ALL).implicit(CALL_CLOBBERED, CALL_ARGS).mem(true, true),
+    info("icall", Flow::IndirectCall, Timing::Icall, &[])
+        .sreg(ALL, ALL).implicit(CALL_CLOBBERED, CALL_ARGS.union(Z_REGS)).mem(true, true),
+    info("eicall", Flow::IndirectCall, Timing::Eicall, &[])
+        .sreg(ALL, ALL).implicit(CALL_CLOBBERED, CALL_ARGS.union(Z_REGS)).mem(true, true),
+    info("ret", Flow::Return, Timing::Ret, &[])
+        .implicit(RegSet::empty(), RET_USES).mem(true, false),
+    info("reti", Flow::Return, Timing::Ret, &[])
+        .sreg(I, ALL).implicit(RegSet::empty(), RegSet::all()).mem(true, false),
+    info("cpse", Flow::Skip, Timing::Skip, &[U, U]),
+    info("sbrc", Flow::Skip, Timing::Skip, &[U, N]),
+    info("sbrs", Flow::Skip, Timing::Skip, &[U, N]),
+    info("sbic", Flow::Skip, Timing::Skip, &[N, N]).mem(true, false),
+    info("sbis", Flow::Skip, Timing::Skip, &[N, N]).mem(true, false),
+    info("brbs", Flow::Branch, Timing::Branch, &[N, N]).sreg(0, ALL),
+    info("brbc", Flow::Branch, Timing::Branch, &[N, N]).sreg(0, ALL),
+    branch("breq", Z),
+    branch("brne", Z),
+    branch("brcs", C),
+    branch("brcc", C),
+    branch("brlo", C),
+    branch("brsh", C),
+    branch("brmi", FN),
+    branch("brpl", FN),
+    branch("brge", S),
+    branch("brlt", S),
+    branch("brhs", H),
+    branch("brhc", H),
+    branch("brts", T),
+    branch("brtc", T),
+    branch("brvs", V),
+    branch("brvc", V),
+    branch("brie", I),
+    branch("brid", I),
+    info("ld", Flow::Next, Timing::Ld, &[D, U]).mem(true, false),
+    info("ldd", Flow::Next, Timing::Ldd, &[D, U]).mem(true, false),
+    info("lds", Flow::Next, Timing::Lds, &[D, N]).mem(true, false),
+    info("st", Flow::Next, Timing::St, &[U, U]).mem(false, true),
+    info("std", Flow::Next, Timing::Std, &[U, U]).mem(false, true),
+    info("sts", Flow::Next, Timing::Sts, &[N, U]).mem(false, true),
+    info("lpm", Flow::Next, Timing::Lpm, &[D, U]),
+    info("elpm", Flow::Next, Timing::Lpm, &[D, U]),
+    info("spm", Flow::Next, Timing::Spm, &[U])
+        .implicit(RegSet::empty(), RegSet::empty().with_pair(0).union(Z_REGS)),
+    info("in", Flow::Next, Timing::One, &[D, N]).mem(true, false),
+    info("out", Flow::Next, Timing::One, &[N, U]).mem(false, true),
+    info("push", Flow::Next, Timing::Push, &[U]).mem(false, true),
+    info("pop", Flow::Next, Timing::Pop, &[D]).mem(true, false),
+    info("xch", Flow::Next, Timing::Atomic, &[U, DU]).mem(true, true),
+    info("las", Flow::Next, Timing::Atomic, &[U, DU]).mem(true, true),
+    info("lac", Flow::Next, Timing::Atomic, &[U, DU]).mem(true, true),
+    info("lat", Flow::Next, Timing::Atomic, &[U, DU]).mem(true, true),
+    info("sbi", Flow::Next, Timing::SbiCbi, &[N, N]).mem(true, true),
+    info("cbi", Flow::Next, Timing::SbiCbi, &[N, N]).mem(true, true),
+    info("bset", Flow::Next, Timing::One, &[N]).sreg(ALL, 0),
+    info("bclr", Flow::Next, Timing::One, &[N]).sreg(ALL, 0),
+    flag("sec", C),
+    flag("clc", C),
+    flag("sen", FN),
+    flag("cln", FN),
+    flag("sez", Z),
+    flag("clz", Z),
+    flag("sei", I),
+    flag("cli", I),
+    flag("ses", S),
+    flag("cls", S),
+    flag("sev", V),
+    flag("clv", V),
+    flag("set", T),
+    flag("clt", T),
+    flag("seh", H),
+    flag("clh", H),
+    info("nop", Flow::Next, Timing::One, &[]),
+    info("sleep", Flow::Next, Timing::One, &[]),
+    info("wdr", Flow::Next, Timing::One, &[]),
+    info("break", Flow::Next, Timing::One, &[]),
+];
+
+/// Info for the implicit-operand `lpm`/`elpm` forms (r0, Z).
+static LPM_R0: InsnInfo =
+    info("lpm", Flow::Next, Timing::Lpm, &[]).implicit(RegSet::empty().with(0), Z_REGS);
+static ELPM_R0: InsnInfo =
+    info("elpm", Flow::Next, Timing::Lpm, &[]).implicit(RegSet::empty().with(0), Z_REGS);
+
+/// Get the conditional branch with the inverted condition.
+pub fn inverted_branch(name: &str) -> Option<&'static str> {
+    const PAIRS: &[(&str, &str)] = &[
+        ("breq", "brne"),
+        ("brcs", "brcc"),
+        ("brlo", "brsh"),
+        ("brmi", "brpl"),
+        ("brge", "brlt"),
+        ("brhs", "brhc"),
+        ("brts", "brtc"),
+        ("brvs", "brvc"),
+        ("brie", "brid"),
+        ("brbs", "brbc"),
+    ];
+    PAIRS.iter().find_map(|&(a, b)| {
+        if name == a {
+            Some(b)
+        } else if name == b {
+            Some(a)
+        } else {
+            None
+        }
+    })
+}
+
+/// Look up the static properties of an instruction.
+/// Returns `None` for data (`.word`) and unknown mnemonics.
+pub fn insn_info(name: &str, ops: &[Operand]) -> Option<&'static InsnInfo> {
+    match (name, ops.len()) {
+        ("lpm", 0) => Some(&LPM_R0),
+        ("elpm", 0) => Some(&ELPM_R0),
+        _ => INSN_INFO.iter().find(|i| i.name == name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operand::PtrReg;
+
+    #[test]
+    fn test_regset() {
+        assert_eq!(RegSet::range(18, 27).iter().count(), 10);
+        assert_eq!(RegSet::range(0, 31), RegSet::all());
+        assert_eq!(
+            RegSet::empty().with_pair(24).with(1).to_string(),
+            "r1 r24 r25"
+        );
+        assert!(!RegSet::empty().contains(32));
+    }
+
+    #[test]
+    fn test_regs() {
+        let ops = [Operand::Reg(24), Operand::Ptr(PtrReg::X, PtrMode::PostInc)];
+        let ld = insn_info("ld", &ops).unwrap();
+        assert_eq!(ld.reg_defs(&ops).to_string(), "r24 r26 r27");
+        assert_eq!(ld.reg_uses(&ops).to_string(), "r26 r27");
+        assert!(ld.mem_read() && !ld.mem_write());
+
+        let ops = [Operand::Reg(24), Operand::Reg(22)];
+        let add = insn_info("add", &ops).unwrap();
+        assert_eq!(add.reg_defs(&ops).to_string(), "r24");
+        assert_eq!(add.reg_uses(&ops).to_string(), "r22 r24");
+        assert_eq!(add.sreg_defs(), sreg::HSVNZC);
+        assert_eq!(insn_info("adc", &ops).unwrap().sreg_uses(), sreg::C);
+
+        let lpm = insn_info("lpm", &[]).unwrap();
+        assert_eq!(lpm.reg_defs(&[]).to_string(), "r0");
+        assert_eq!(lpm.reg_uses(&[]).to_string(), "r30 r31");
+
+        let icall = insn_info("icall", &[]).unwrap();
+        assert_eq!(icall.flow(), Flow::IndirectCall);
+        assert!(icall.reg_uses(&[]).contains(30));
+        assert!(icall.reg_defs(&[]).contains(18));
+        assert!(!icall.reg_defs(&[]).contains(28));
+
+        assert!(insn_info(".word", &[Operand::Imm(0)]).is_none());
+    }
+
+    #[test]
+    fn test_cycles() {
+        let call = insn_info("call", &[Operand::Label("f".to_string())]).unwrap();
+        assert_eq!(call.cycles(CoreFamily::Avre), (4, 4));
+        assert_eq!(call.cycles(CoreFamily::from_arch("avr6")), (5, 5));
+        assert_eq!(call.cycles(CoreFamily::from_arch("avrxmega3")), (3, 3));
+        let breq = insn_info("breq", &[Operand::RelOffset(0)]).unwrap();
+        assert_eq!(breq.flow(), Flow::Branch);
+        assert_eq!(breq.cycles(CoreFamily::Avre), (1, 2));
+        let sbrc = insn_info("sbrc", &[Operand::Reg(24), Operand::Bit(0)]).unwrap();
+        assert_eq!(sbrc.flow(), Flow::Skip);
+        assert_eq!(sbrc.cycles(CoreFamily::Avre), (1, 3));
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -0,0 +1,548 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+//! Address assignment of the final program and control transfer range checks.
+
+use crate::{
+    callgraph::{CallGraph, RefKind, number_addrs},
+    insninfo::{Flow, inverted_branch},
+    opcodes::insn_size_words,
+    operand::{ExprFunc, Operand},
+    program::{Insn, InsnPatch, Part, PinnedAddr, Program},
+};
+use anyhow::{self as ah, Context as _, format_err as err};
+use std::collections::HashMap;
+
+/// Label of the start of the text section.
+pub const TEXT_LABEL: &str = "____section_text__";
+/// Label of the start of the data load image, behind the text section.
+pub const DATA_LABEL: &str = "____section_data__";
+
+/// Maximum number of layout iterations.
+const MAX_ITERATIONS: usize = 100;
+
+/// Word addresses of all labels of the program with all patches applied.
+#[derive(Clone, Debug)]
+pub struct Layout {
+    labels: HashMap<String, u32>,
+    text_words: u32,
+}
+
+impl Layout {
+    pub fn new(program: &Program) -> ah::Result<Self> {
+        let Some(text) = program.section_text() else {
+            return Err(err!("No .text section."));
+        };
+
+        let mut labels = HashMap::new();
+        let mut add_label = |label: &str, addr: u32| -> ah::Result<()> {
+            if labels.insert(label.to_string(), addr).is_some() {
+                Err(err!("Duplicate label '{label}'."))
+            } else {
+                Ok(())
+            }
+        };
+
+        let mut addr = 0;
+        add_label(TEXT_LABEL, addr)?;
+        for part in text.final_parts() {
+            add_label(part.name(), addr)?;
+            for insn in part.final_insns() {
+                if let Some(label) = insn.label() {
+                    add_label(label, addr)?;
+                }
+                let Some(size) = insn_size_words(insn.name()) else {
+                    return Err(err!("Unknown instruction '{insn}'."));
+                };
+                addr += size;
+            }
+        }
+        add_label(DATA_LABEL, addr)?;
+
+        Ok(Self {
+            labels,
+            text_words: addr,
+        })
+    }
+
+    /// Word address of a label.
+    pub fn label_addr(&self, label: &str) -> Option<u32> {
+        self.labels.get(label).copied()
+    }
+
+    /// Word address of a label, or an error if it does not exist.
+    pub fn resolve(&self, label: &str) -> ah::Result<u32> {
+        self.label_addr(label)
+            .ok_or_else(|| err!("Label '{label}' not found."))
+    }
+
+    /// Check that all pinned labels are still at their original address.
+    pub fn check_pinned(&self, program: &Program) -> ah::Result<()> {
+        for pin in program.pinned_addrs() {
+            let Some(addr) = self.label_addr(&pin.label) else {
+                return Err(err!(
+                    "'{}' at 0x{:X} was removed, but its address is used \
+                     as a plain number ({} reference).",
+                    pin.label,
+                    pin.addr * 2,
+                    pin.reason
+                ));
+            };
+            if addr != pin.addr {
+                return Err(err!(
+                    "'{}' moved from 0x{:X} to 0x{:X}, but its address is used \
+                     as a plain number ({} reference).",
+                    pin.label,
+                    pin.addr * 2,
+                    addr * 2,
+                    pin.reason
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the code addresses loaded as 16 bit word addresses are
+    /// reachable through Z. eicall and eijmp use EIND, which is assumed to be 0,
+    /// unless the third address byte is loaded, too.
+    pub fn check_code_ptrs(&self, program: &Program) -> ah::Result<()> {
+        let Some(text) = program.section_text() else {
+            return Ok(());
+        };
+        let exprs = || {
+            text.final_parts()
+                .flat_map(|part| part.final_insns())
+                .flat_map(|insn| insn.ops().iter().map(move |op| (insn, op)))
+                .filter_map(|(insn, op)| match op {
+                    Operand::Expr(expr) if expr.pm => Some((insn, expr)),
+                    _ => None,
+                })
+        };
+        let hh8_labels: Vec<&str> = exprs()
+            .filter(|(_, expr)| expr.func == ExprFunc::Hh8)
+            .map(|(_, expr)| expr.label.as_str())
+            .collect();
+        for (insn, expr) in exprs() {
+            if expr.func != ExprFunc::Hi8 || hh8_labels.contains(&expr.label.as_str()) {
+                continue;
+            }
+            let addr = self.resolve(&expr.label)?;
+            if addr > 0xFFFF {
+                return Err(err!(
+                    "'{insn}': '{}' moved to 0x{:X}, out of reach of Z.",
+                    expr.label,
+                    addr * 2
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Size of the text section in words.
+    pub fn text_words(&self) -> u32 {
+        self.text_words
+    }
+}
+
+/// Pin the parts whose address is used as a plain number, that doesn't follow
+/// the part when it moves. Must be called on the unpatched program.
+pub fn pin_addrs(program: &mut Program) {
+    let graph = CallGraph::new(program);
+    let Some(text) = program.section_text() else {
+        return;
+    };
+    let mut pinned: Vec<PinnedAddr> = vec![];
+    let mut pin = |part: &Part, reason| {
+        if let Some(first) = part.insns().first()
+            && !pinned.iter().any(|p| p.label == part.name())
+        {
+            pinned.push(PinnedAddr {
+                label: part.name().to_string(),
+                addr: first.addr() / 2,
+                reason,
+            });
+        }
+    };
+    for edge in graph.edges() {
+        if let Some(to) = edge.to
+            && matches!(edge.kind, RefKind::Immediate | RefKind::Data)
+        {
+            pin(text.part_at(to), edge.kind.name());
+        }
+    }
+    // The trampoline addresses loaded as gs() immediates for eicall/eijmp
+    // are relocated. The trampolines can't move, if an address is a plain number.
+    if let Some(part) = text.find_part("__trampolines_start") {
+        let numbers = number_addrs(program);
+        if part
+            .insns()
+            .iter()
+            .any(|insn| numbers.contains(&(insn.addr() / 2)))
+        {
+            pin(part, "trampoline");
+        }
+    }
+    program.set_pinned_addrs(pinned);
+}
+
+/// Relative jump range of an instruction in words, as (min, max) offset.
+pub fn rel_range(name: &str) -> Option<(i64, i64)> {
+    match name {
+        "rjmp" | "rcall" => Some((-2048, 2047)),
+        "brbs" | "brbc" => Some((-64, 63)),
+        name if inverted_branch(name).is_some() => Some((-64, 63)),
+        _ => None,
+    }
+}
+
+/// Check whether a relative jump from `addr` to `target` (word addresses) is encodable.
+/// On devices with up to 8 KiB of flash, rjmp and rcall wrap around the flash end.
+pub fn rel_in_range(name: &str, addr: u32, target: u32, flash_size: u32) -> bool {
+    let Some((min, max)) = rel_range(name) else {
+        return true;
+    };
+    let offset = target as i64 - (addr as i64 + 1);
+    if (min..=max).contains(&offset) {
+        return true;
+    }
+    let flash_words = flash_size as i64 / 2;
+    if flash_words > 0 && flash_words <= (max - min + 1) {
+        let o = offset.rem_euclid(flash_words);
+        let o = if o >= flash_words / 2 {
+            o - flash_words
+        } else {
+            o
+        };
+        return (min..=max).contains(&o);
+    }
+    false
+}
+
+/// The device supports the 2-word jmp and call instructions.
+pub fn has_jmp(flash_size: u32) -> bool {
+    flash_size > 8 * 1024
+}
+
+/// Range fix of one out-of-range instruction.
+enum Fix {
+    /// rjmp/rcall to jmp/call.
+    Long,
+    /// Inverted conditional branch over a jump.
+    Branch,
+}
+
+/// Apply all patches, assign the final addresses to all instructions
+/// and make sure all relative jumps and branches are in range.
+///
+/// Out of range rjmp/rcall are converted to jmp/call.
+/// Out of range conditional branches are converted to an inverted branch
+/// over an rjmp or jmp to the original target.
+pub fn layout_program(program: &mut Program) -> ah::Result<()> {
+    let Some(device) = program.device() else {
+        return Err(err!("No device info."));
+    };
+    let flash_size = device.flash_size;
+
+    for _ in 0..MAX_ITERATIONS {
+        let Some(text) = program.section_text_mut() else {
+            return Err(err!("No .text section."));
+        };
+        text.apply_patches()?;
+        let layout = Layout::new(program)?;
+        layout.check_pinned(program)?;
+        layout.check_code_ptrs(program)?;
+        let text = program.section_text_mut().unwrap();
+
+        // Assign the addresses and find the out of range instructions.
+        let mut fixes = vec![];
+        let mut addr = 0;
+        for (p, part) in text.parts_mut().iter_mut().enumerate() {
+            for (i, insn) in part.insns_mut().iter_mut().enumerate() {
+                insn.set_addr(addr * 2);
+                if let Some(target) = insn.ops().last().and_then(|op| op.label())
+                    && rel_range(insn.name()).is_some()
+                {
+                    let target = layout
+                        .resolve(target)
+                        .with_context(|| format!("'{insn}' at 0x{:X}", addr * 2))?;
+                    if !rel_in_range(insn.name(), addr, target, flash_size) {
+                        let fix = match insn.flow() {
+                            Flow::Branch => Fix::Branch,
+                            _ => Fix::Long,
+                        };
+                        fixes.push((p, i, fix));
+                    }
+                }
+                addr += insn.size_words();
+            }
+        }
+        if fixes.is_empty() {
+            return Ok(());
+        }
+
+        for (p, i, fix) in fixes {
+            let part = text.part_at_mut(p);
+            let insn = part.insn_at(i).clone();
+            let context = format!("'{insn}' at 0x{:X} in '{}'", insn.addr(), part.name());
+            let patch = match fix {
+                Fix::Long => {
+                    if !has_jmp(flash_size) {
+                        return Err(err!("{context}: Jump target is out of range."));
+                    }
+                    if part.in_skip_shadow(i) {
+                        return Err(err!(
+                            "{context}: Out of range jump can't be converted to a \
+                             2-word jump in a skip instruction shadow."
+                        ));
+                    }
+                    let mut long = insn.clone();
+                    long.set_name(if insn.name() == "rcall" {
+                        "call"
+                    } else {
+                        "jmp"
+                    });
+                    vec![long]
+                }
+                Fix::Branch => {
+                    // Branch over an rjmp to the original target.
+                    // The rjmp is converted to jmp in the next iteration, if required.
+                    if part.in_skip_shadow(i) {
+                        return Err(err!(
+                            "{context}: Out of range branch can't be rewritten \
+                             in a skip instruction shadow."
+                        ));
+                    }
+                    let Some(inv) = inverted_branch(insn.name()) else {
+                        return Err(err!("{context}: Branch can't be inverted."));
+                    };
+                    let skip_label = if i + 1 < part.insns().len() {
+                        let next = part.insn_at_mut(i + 1);
+                        if next.label().is_none() {
+                            let mut label = format!("__brskip{:04X}", next.addr());
+                            while layout.label_addr(&label).is_some() {
+                                label.push('_');
+                            }
+                            next.set_label(Some(label));
+                        }
+                        next.label().unwrap().to_string()
+                    } else {
+                        match text.parts().get(p + 1) {
+                            Some(next) => next.name().to_string(),
+                            None => DATA_LABEL.to_string(),
+                        }
+                    };
+                    let target = insn.ops().last().unwrap().clone();
+                    let mut branch = insn.clone();
+                    branch.set_name(inv);
+                    let last = branch.ops().len() - 1;
+                    branch.set_op(last, Operand::Label(skip_label));
+                    vec![branch, Insn::new("rjmp", vec![target], None, insn.addr())]
+                }
+            };
+            text.part_at_mut(p)
+                .insn_at_mut(i)
+                .set_patch(Some(InsnPatch::new(patch)));
+        }
+    }
+    Err(err!("Program layout did not converge."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{part_asm, program, program_with_data};
+
+    fn nops(count: usize) -> String {
+        "    nop\n".repeat(count)
+    }
+
+    #[test]
+    fn test_in_range() {
+        let mut program = program(
+            "atmega328p",
+            &format!(
+                "__vectors:\n    jmp main\nmain:\n    breq l\n{}    l: rjmp main\n",
+                nops(63)
+            ),
+        );
+        layout_program(&mut program).unwrap();
+        let asm = part_asm(&program, "main");
+        assert_eq!(asm[0], "breq l");
+        assert_eq!(asm.last().unwrap(), "l: rjmp main");
+    }
+
+    #[test]
+    fn test_branch_inversion() {
+        let mut program = program(
+            "atmega328p",
+            &format!(
+                "__vectors:\n    jmp main\nmain:\n    breq l\n{}    l: rjmp main\n",
+                nops(64)
+            ),
+        );
+        layout_program(&mut program).unwrap();
+        let asm = part_asm(&program, "main");
+        assert_eq!(asm[0], "brne __brskip0006");
+        assert_eq!(asm[1], "rjmp l");
+        assert_eq!(asm[2], "__brskip0006: nop");
+        let layout = Layout::new(&program).unwrap();
+        assert_eq!(layout.label_addr("l"), Some(2 + 2 + 64));
+    }
+
+    #[test]
+    fn test_long_jump() {
+        // The inverted branch gets a jmp, if the rjmp is out of range, too.
+        let mut program = program(
+            "atmega328p",
+            &format!(
+                "__vectors:\n    jmp main\nmain:\n    rcall f\n    brcs f\n{}f:\n    ret\n",
+                nops(2100)
+            ),
+        );
+        layout_program(&mut program).unwrap();
+        let asm = part_asm(&program, "main");
+        assert_eq!(asm[0], "call f");
+        assert_eq!(asm[1], "brcc __brskip0008");
+        assert_eq!(asm[2], "jmp f");
+        assert_eq!(asm[3], "__brskip0008: nop");
+    }
+
+    #[test]
+    fn test_skip_shadow() {
+        let mut program = program(
+            "atmega328p",
+            &format!(
+                "__vectors:\n    jmp main\nmain:\n    sbrc r24, 0\n    rjmp f\n{}f:\n    ret\n",
+                nops(2100)
+            ),
+        );
+        let e = layout_program(&mut program).unwrap_err();
+        assert!(e.to_string().contains("skip instruction shadow"), "{e}");
+    }
+
+    #[test]
+    fn test_pinned() {
+        let asm = "
+__vectors:
+    jmp main
+unused:
+    ret
+__target:
+    ret
+main:
+    ldi r24, 0x03
+    ldi r25, 0x00
+    ret
+";
+        // The unrelocated number pins the target.
+        let mut program = program("atmega328p", asm);
+        let text = program.section_text_mut().unwrap();
+        text.find_part_mut("unused")
+            .unwrap()
+            .set_patch_delete_part();
+        let e = layout_program(&mut program).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "'__target' moved from 0x6 to 0x4, but its address is used \
+             as a plain number (immediate reference)."
+        );
+
+        // Changes behind the target are fine.
+        let mut program = crate::testutil::program("atmega328p", asm);
+        let text = program.section_text_mut().unwrap();
+        text.find_part_mut("main")
+            .unwrap()
+            .insn_at_mut(0)
+            .set_patch(Some(InsnPatch::empty()));
+        layout_program(&mut program).unwrap();
+    }
+
+    #[test]
+    fn test_trampolines() {
+        let asm = "
+__vectors:
+    jmp main
+    jmp __bad_interrupt
+__trampolines_start:
+    jmp f
+    jmp g
+__bad_interrupt:
+    jmp __vectors
+main:
+    ldi r30, 0x06
+    ldi r31, 0x00
+    eicall
+    ret
+f:
+    ret
+g:
+    ret
+";
+        let truncate = |program: &mut Program| {
+            let text = program.section_text_mut().unwrap();
+            text.find_part_mut("__vectors")
+                .unwrap()
+                .insn_at_mut(1)
+                .set_patch(Some(InsnPatch::empty()));
+        };
+
+        // The gs() immediate is relocated and the trampolines can move.
+        let mut program = program("atmega2560", asm);
+        assert!(program.pinned_addrs().is_empty());
+        truncate(&mut program);
+        layout_program(&mut program).unwrap();
+        assert_eq!(
+            part_asm(&program, "main")[..2],
+            ["ldi r30, low(__reltgt0000)", "ldi r31, high(__reltgt0000)"]
+        );
+        let layout = Layout::new(&program).unwrap();
+        assert_eq!(layout.label_addr("__reltgt0000"), Some(4));
+
+        // A trampoline address is also used as a plain number.
+        let mut program = program_with_data("atmega2560", asm, &[0x06, 0x00]);
+        truncate(&mut program);
+        let e = layout_program(&mut program).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "'__trampolines_start' moved from 0x8 to 0x4, but its address is used \
+             as a plain number (trampoline reference)."
+        );
+    }
+
+    #[test]
+    fn test_code_ptr_reach() {
+        let asm = format!(
+            "
+__vectors:
+    jmp main
+main:
+    ldi r30, low(f)
+    ldi r31, high(f)
+    eicall
+    ret
+fill:
+{}f:
+    ret
+",
+            nops(0xFFF9)
+        );
+        let mut program = program("atmega2560", &asm);
+        layout_program(&mut program).unwrap();
+
+        // An inserted instruction pushes f out of reach.
+        let text = program.section_text_mut().unwrap();
+        let main = text.find_part_mut("main").unwrap();
+        let nop = Insn::new("nop", vec![], None, 0);
+        let ret = main.insn_at(3).clone();
+        main.insn_at_mut(3)
+            .set_patch(Some(InsnPatch::new(vec![nop, ret])));
+        let e = layout_program(&mut program).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "'ldi r31, high(f)': 'f' moved to 0x20000, out of reach of Z."
+        );
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -0,0 +1,186 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+//! Register and SREG flag liveness within a single `Part`.
+
+use crate::{
+    cfg::{Cfg, EdgeTarget},
+    insninfo::{RegSet, sreg},
+    program::Insn,
+};
+
+/// Set of registers and SREG flags.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct LiveSet {
+    pub regs: RegSet,
+    pub sreg: u8,
+}
+
+impl LiveSet {
+    pub const fn empty() -> Self {
+        Self {
+            regs: RegSet::empty(),
+            sreg: 0,
+        }
+    }
+
+    pub const fn all() -> Self {
+        Self {
+            regs: RegSet::all(),
+            sreg: sreg::ALL,
+        }
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            regs: self.regs.union(other.regs),
+            sreg: self.sreg | other.sreg,
+        }
+    }
+
+    pub fn without(self, other: Self) -> Self {
+        Self {
+            regs: self.regs.without(other.regs),
+            sreg: self.sreg & !other.sreg,
+        }
+    }
+
+    /// Registers and flags written by an instruction.
+    pub fn defs(insn: &Insn) -> Self {
+        Self {
+            regs: insn.reg_defs(),
+            sreg: insn.sreg_defs(),
+        }
+    }
+
+    /// Registers and flags read by an instruction.
+    pub fn uses(insn: &Insn) -> Self {
+        Self {
+            regs: insn.reg_uses(),
+            sreg: insn.sreg_uses(),
+        }
+    }
+}
+
+/// Backward liveness of the instructions of a part.
+#[derive(Clone, Debug)]
+pub struct Liveness {
+    live_in: Vec<LiveSet>,
+    live_out: Vec<LiveSet>,
+}
+
+impl Liveness {
+    /// Compute the liveness with the instruction effects from the metadata table.
+    /// Everything is live at edges leaving the part.
+    pub fn new(insns: &[Insn], cfg: &Cfg) -> Self {
+        Self::with_effects(insns, cfg, LiveSet::all(), |_, insn| {
+            (LiveSet::defs(insn), LiveSet::uses(insn))
+        })
+    }
+
+    /// Compute the liveness with custom instruction effects.
+    /// `effects` returns the (defs, uses) of the instruction at an index.
+    /// `exit` is live at all edges leaving the part.
+    pub fn with_effects(
+        insns: &[Insn],
+        cfg: &Cfg,
+        exit: LiveSet,
+        effects: impl Fn(usize, &Insn) -> (LiveSet, LiveSet),
+    ) -> Self {
+        let effects: Vec<_> = insns
+            .iter()
+            .enumerate()
+            .map(|(i, insn)| effects(i, insn))
+            .collect();
+        let mut live_in = vec![LiveSet::empty(); insns.len()];
+        let mut live_out = vec![LiveSet::empty(); insns.len()];
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in cfg.blocks().iter().rev() {
+                let mut live = LiveSet::empty();
+                for edge in block.succs() {
+                    live = live.union(match edge.target {
+                        EdgeTarget::Block(b) => live_in[cfg.block(b).start()],
+                        _ => exit,
+                    });
+                }
+                for i in block.range().rev() {
+                    live_out[i] = live;
+                    let (defs, uses) = effects[i];
+                    live = live.without(defs).union(uses);
+                    if live_in[i] != live {
+                        live_in[i] = live;
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        Self { live_in, live_out }
+    }
+
+    /// Live set before the instruction at `index`.
+    pub fn live_in(&self, index: usize) -> LiveSet {
+        self.live_in[index]
+    }
+
+    /// Live set after the instruction at `index`.
+    pub fn live_out(&self, index: usize) -> LiveSet {
+        self.live_out[index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::program;
+
+    const ASM: &str = "
+f:
+    ldi r18, 0x01
+    cpi r24, 0x00
+    breq done
+    mov r18, r24
+    done: mov r24, r18
+    rjmp g
+";
+
+    #[test]
+    fn test_branch() {
+        let program = program("atmega328p", ASM);
+        let part = program.section_text().unwrap().find_part("f").unwrap();
+        let live = Liveness::new(part.insns(), &Cfg::new(part));
+
+        // Both paths out of the branch are live.
+        let out = live.live_out(2);
+        assert!(out.regs.contains(18) && out.regs.contains(24));
+        assert_eq!(out.sreg, sreg::ALL);
+        assert_eq!(live.live_out(1).sreg & sreg::Z, sreg::Z);
+        assert!(!live.live_in(3).regs.contains(18));
+        assert!(!live.live_in(0).regs.contains(18));
+        assert!(live.live_in(0).regs.contains(24));
+        // Everything is live when leaving the part.
+        assert_eq!(live.live_out(5), LiveSet::all());
+    }
+
+    #[test]
+    fn test_effects() {
+        let program = program("atmega328p", ASM);
+        let part = program.section_text().unwrap().find_part("f").unwrap();
+        let live = Liveness::with_effects(
+            part.insns(),
+            &Cfg::new(part),
+            LiveSet::empty(),
+            |_, insn| (LiveSet::defs(insn), LiveSet::uses(insn)),
+        );
+        assert_eq!(live.live_out(5), LiveSet::empty());
+        assert!(!live.live_out(4).regs.contains(24));
+        assert_eq!(live.live_in(4).regs.iter().collect::<Vec<_>>(), [18]);
+        assert_eq!(live.live_out(2).sreg, 0);
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
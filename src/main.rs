@@ -3,12 +3,14 @@
 // Copyright (C) 2025 Michael Büsch <m@bues.ch>
 
 use crate::{
-    asm::assemble_hex,
+    asm::{Backend, assemble_avra, assemble_gnu, assemble_native, gnu_elf_path},
+    callgraph::CallGraph,
     dasm::{disassemble_elf_text, extract_elf_data},
-    patch::patch_program,
+    layout::{layout_program, pin_addrs},
+    patch::{patch_program, steps_help},
     program::Program,
 };
-use anyhow::{self as ah, Context as _};
+use anyhow::{self as ah, Context as _, format_err as err};
 use clap::Parser;
 use std::path::PathBuf;
 use tokio::{fs::OpenOptions, io::AsyncWriteExt as _};
@@ -16,9 +18,27 @@ use tokio::{fs::OpenOptions, io::AsyncWriteExt as _};
 mod abi;
 mod asm;
 mod avr_deviceinfo;
+mod callgraph;
+mod cfg;
 mod dasm;
+mod devices;
+mod ihex;
+mod insninfo;
+mod layout;
+mod liveness;
+mod opcodes;
+mod operand;
 mod patch;
 mod program;
+#[cfg(test)]
+mod testutil;
+
+/// Analysis report printed after patching.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Report {
+    /// Calls and jumps between the parts of the program.
+    CallGraph,
+}
 
 #[derive(Parser, Debug)]
 struct Opts {
@@ -26,11 +46,30 @@ struct Opts {
 
     output: PathBuf,
 
-    #[arg(short = 'P', long)]
+    /// Patch step to run. May be given multiple times.
+    /// See --help for the list of steps.
+    #[arg(short = 'P', long, value_name = "STEP[:PARAM=VALUE,...]", long_help = steps_help())]
     patch: Vec<String>,
 
     #[arg(short = 'A', long)]
     dump_asm: Option<String>,
+
+    /// Assembler backend used to generate the output file.
+    #[arg(short = 'B', long, value_enum, default_value_t = Backend::Native)]
+    backend: Backend,
+
+    /// ELF output file of the GNU backend.
+    /// Defaults to the output file name with .elf extension.
+    #[arg(long)]
+    output_elf: Option<PathBuf>,
+
+    /// Print an analysis report of the patched program.
+    #[arg(short = 'R', long, value_enum)]
+    report: Vec<Report>,
+
+    /// Cross-check the built-in disassembler against avr-objdump.
+    #[arg(long)]
+    objdump_check: bool,
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -43,23 +82,43 @@ async fn main() -> ah::Result<()> {
         .await
         .context("Extract .data section")?;
 
-    disassemble_elf_text(&mut program, &opts.input_elf)
+    disassemble_elf_text(&mut program, &opts.input_elf, opts.objdump_check)
         .await
         .context("Disassemble program")?;
 
     program.fixup_data_load_addr().context("Fixup .data")?;
+    pin_addrs(&mut program);
 
     patch_program(&mut program, &opts.patch)
         .await
         .context("Patch program")?;
 
-    let asm_text = program
-        .to_asm()
-        .context("Convert program to assembly code")?;
+    layout_program(&mut program).context("Lay out program")?;
 
-    assemble_hex(&asm_text, &opts.output)
-        .await
-        .context("Assemble program")?;
+    for report in &opts.report {
+        match report {
+            Report::CallGraph => print!("{}", CallGraph::new(&program).report(&program)),
+        }
+    }
+
+    let asm_text = match opts.backend {
+        Backend::Native | Backend::Avra => program.to_asm(),
+        Backend::Gnu => program.to_gnu_asm(),
+    }
+    .context("Convert program to assembly code")?;
+
+    match opts.backend {
+        Backend::Native => assemble_native(&program, &opts.output).await,
+        Backend::Avra => assemble_avra(&asm_text, &opts.output).await,
+        Backend::Gnu => {
+            let Some(device) = program.device() else {
+                return Err(err!("No device info."));
+            };
+            let out_elf = gnu_elf_path(&opts.output, opts.output_elf.as_deref());
+            assemble_gnu(&asm_text, device, &opts.output, &out_elf).await
+        }
+    }
+    .context("Assemble program")?;
 
     if let Some(dump_asm) = &opts.dump_asm {
         if dump_asm == "-" {
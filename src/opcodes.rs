@@ -0,0 +1,814 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+use crate::operand::{Expr, ExprFunc, Operand, PtrMode, PtrReg, is_label};
+use anyhow::{self as ah, format_err as err};
+use std::sync::LazyLock;
+
+/// Kind of an instruction operand and the opcode bit field it lives in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpKind {
+    /// Register number = base + field * scale.
+    Reg { field: char, base: u8, scale: u8 },
+    /// Hexadecimal immediate.
+    Imm { field: char },
+    /// Decimal bit number.
+    Bit { field: char },
+    /// I/O space address.
+    Io { field: char },
+    /// Data space address.
+    Data { field: char },
+    /// Relative flash word offset.
+    Rel { field: char },
+    /// Absolute flash word address.
+    Abs { field: char },
+    /// Pointer register with displacement.
+    Disp { field: char, ptr: PtrReg },
+    /// Pointer register with fixed addressing mode.
+    Ptr { ptr: PtrReg, mode: PtrMode },
+}
+
+use OpKind::*;
+
+const RD: OpKind = Reg {
+    field: 'd',
+    base: 0,
+    scale: 1,
+};
+const RR: OpKind = Reg {
+    field: 'r',
+    base: 0,
+    scale: 1,
+};
+const RD_HI: OpKind = Reg {
+    field: 'd',
+    base: 16,
+    scale: 1,
+};
+const RR_HI: OpKind = Reg {
+    field: 'r',
+    base: 16,
+    scale: 1,
+};
+const RD_PAIR: OpKind = Reg {
+    field: 'd',
+    base: 0,
+    scale: 2,
+};
+const RR_PAIR: OpKind = Reg {
+    field: 'r',
+    base: 0,
+    scale: 2,
+};
+const RD_ADIW: OpKind = Reg {
+    field: 'd',
+    base: 24,
+    scale: 2,
+};
+const K: OpKind = Imm { field: 'K' };
+const B: OpKind = Bit { field: 'b' };
+const A: OpKind = Io { field: 'A' };
+const REL: OpKind = Rel { field: 'k' };
+const ABS: OpKind = Abs { field: 'k' };
+const DATA: OpKind = Data { field: 'k' };
+const X: OpKind = Ptr {
+    ptr: PtrReg::X,
+    mode: PtrMode::Plain,
+};
+const X_INC: OpKind = Ptr {
+    ptr: PtrReg::X,
+    mode: PtrMode::PostInc,
+};
+const X_DEC: OpKind = Ptr {
+    ptr: PtrReg::X,
+    mode: PtrMode::PreDec,
+};
+const Y: OpKind = Ptr {
+    ptr: PtrReg::Y,
+    mode: PtrMode::Plain,
+};
+const Y_INC: OpKind = Ptr {
+    ptr: PtrReg::Y,
+    mode: PtrMode::PostInc,
+};
+const Y_DEC: OpKind = Ptr {
+    ptr: PtrReg::Y,
+    mode: PtrMode::PreDec,
+};
+const Y_DISP: OpKind = Disp {
+    field: 'q',
+    ptr: PtrReg::Y,
+};
+const Z: OpKind = Ptr {
+    ptr: PtrReg::Z,
+    mode: PtrMode::Plain,
+};
+const Z_INC: OpKind = Ptr {
+    ptr: PtrReg::Z,
+    mode: PtrMode::PostInc,
+};
+const Z_DEC: OpKind = Ptr {
+    ptr: PtrReg::Z,
+    mode: PtrMode::PreDec,
+};
+const Z_DISP: OpKind = Disp {
+    field: 'q',
+    ptr: PtrReg::Z,
+};
+
+/// The AVR opcode table.
+///
+/// The order matters for decoding: The first matching entry wins.
+/// Aliases that avr-objdump prefers are listed before their generic forms.
+#[rustfmt::skip]
+static OPCODE_TABLE: &[(&str, &str, &[OpKind])] = &[
+    ("nop",    "0000 0000 0000 0000", &[]),
+    ("sec",    "1001 0100 0000 1000", &[]),
+    ("sez",    "1001 0100 0001 1000", &[]),
+    ("sen",    "1001 0100 0010 1000", &[]),
+    ("sev",    "1001 0100 0011 1000", &[]),
+    ("ses",    "1001 0100 0100 1000", &[]),
+    ("seh",    "1001 0100 0101 1000", &[]),
+    ("set",    "1001 0100 0110 1000", &[]),
+    ("sei",    "1001 0100 0111 1000", &[]),
+    ("clc",    "1001 0100 1000 1000", &[]),
+    ("clz",    "1001 0100 1001 1000", &[]),
+    ("cln",    "1001 0100 1010 1000", &[]),
+    ("clv",    "1001 0100 1011 1000", &[]),
+    ("cls",    "1001 0100 1100 1000", &[]),
+    ("clh",    "1001 0100 1101 1000", &[]),
+    ("clt",    "1001 0100 1110 1000", &[]),
+    ("cli",    "1001 0100 1111 1000", &[]),
+    ("bset",   "1001 0100 0sss 1000", &[Bit { field: 's' }]),
+    ("bclr",   "1001 0100 1sss 1000", &[Bit { field: 's' }]),
+    ("ijmp",   "1001 0100 0000 1001", &[]),
+    ("eijmp",  "1001 0100 0001 1001", &[]),
+    ("icall",  "1001 0101 0000 1001", &[]),
+    ("eicall", "1001 0101 0001 1001", &[]),
+    ("ret",    "1001 0101 0000 1000", &[]),
+    ("reti",   "1001 0101 0001 1000", &[]),
+    ("sleep",  "1001 0101 1000 1000", &[]),
+    ("break",  "1001 0101 1001 1000", &[]),
+    ("wdr",    "1001 0101 1010 1000", &[]),
+    ("lpm",    "1001 0101 1100 1000", &[]),
+    ("elpm",   "1001 0101 1101 1000", &[]),
+    ("spm",    "1001 0101 1110 1000", &[]),
+    ("spm",    "1001 0101 1111 1000", &[Z_INC]),
+    ("des",    "1001 0100 KKKK 1011", &[K]),
+
+    ("add",    "0000 11rd dddd rrrr", &[RD, RR]),
+    ("adc",    "0001 11rd dddd rrrr", &[RD, RR]),
+    ("sub",    "0001 10rd dddd rrrr", &[RD, RR]),
+    ("sbc",    "0000 10rd dddd rrrr", &[RD, RR]),
+    ("and",    "0010 00rd dddd rrrr", &[RD, RR]),
+    ("or",     "0010 10rd dddd rrrr", &[RD, RR]),
+    ("eor",    "0010 01rd dddd rrrr", &[RD, RR]),
+    ("cp",     "0001 01rd dddd rrrr", &[RD, RR]),
+    ("cpc",    "0000 01rd dddd rrrr", &[RD, RR]),
+    ("cpse",   "0001 00rd dddd rrrr", &[RD, RR]),
+    ("mov",    "0010 11rd dddd rrrr", &[RD, RR]),
+    ("mul",    "1001 11rd dddd rrrr", &[RD, RR]),
+    ("movw",   "0000 0001 dddd rrrr", &[RD_PAIR, RR_PAIR]),
+    ("muls",   "0000 0010 dddd rrrr", &[RD_HI, RR_HI]),
+    ("mulsu",  "0000 0011 0ddd 0rrr", &[RD_HI, RR_HI]),
+    ("fmul",   "0000 0011 0ddd 1rrr", &[RD_HI, RR_HI]),
+    ("fmuls",  "0000 0011 1ddd 0rrr", &[RD_HI, RR_HI]),
+    ("fmulsu", "0000 0011 1ddd 1rrr", &[RD_HI, RR_HI]),
+
+    ("cpi",    "0011 KKKK dddd KKKK", &[RD_HI, K]),
+    ("sbci",   "0100 KKKK dddd KKKK", &[RD_HI, K]),
+    ("subi",   "0101 KKKK dddd KKKK", &[RD_HI, K]),
+    ("ori",    "0110 KKKK dddd KKKK", &[RD_HI, K]),
+    ("andi",   "0111 KKKK dddd KKKK", &[RD_HI, K]),
+    ("ldi",    "1110 KKKK dddd KKKK", &[RD_HI, K]),
+    ("adiw",   "1001 0110 KKdd KKKK", &[RD_ADIW, K]),
+    ("sbiw",   "1001 0111 KKdd KKKK", &[RD_ADIW, K]),
+
+    ("com",    "1001 010d dddd 0000", &[RD]),
+    ("neg",    "1001 010d dddd 0001", &[RD]),
+    ("swap",   "1001 010d dddd 0010", &[RD]),
+    ("inc",    "1001 010d dddd 0011", &[RD]),
+    ("asr",    "1001 010d dddd 0101", &[RD]),
+    ("lsr",    "1001 010d dddd 0110", &[RD]),
+    ("ror",    "1001 010d dddd 0111", &[RD]),
+    ("dec",    "1001 010d dddd 1010", &[RD]),
+    ("push",   "1001 001d dddd 1111", &[RD]),
+    ("pop",    "1001 000d dddd 1111", &[RD]),
+    ("xch",    "1001 001d dddd 0100", &[Z, RD]),
+    ("las",    "1001 001d dddd 0101", &[Z, RD]),
+    ("lac",    "1001 001d dddd 0110", &[Z, RD]),
+    ("lat",    "1001 001d dddd 0111", &[Z, RD]),
+
+    ("in",     "1011 0AAd dddd AAAA", &[RD, A]),
+    ("out",    "1011 1AAr rrrr AAAA", &[A, RR]),
+    ("cbi",    "1001 1000 AAAA Abbb", &[A, B]),
+    ("sbic",   "1001 1001 AAAA Abbb", &[A, B]),
+    ("sbi",    "1001 1010 AAAA Abbb", &[A, B]),
+    ("sbis",   "1001 1011 AAAA Abbb", &[A, B]),
+    ("bld",    "1111 100d dddd 0bbb", &[RD, B]),
+    ("bst",    "1111 101d dddd 0bbb", &[RD, B]),
+    ("sbrc",   "1111 110r rrrr 0bbb", &[RR, B]),
+    ("sbrs",   "1111 111r rrrr 0bbb", &[RR, B]),
+
+    ("brcs",   "1111 00kk kkkk k000", &[REL]),
+    ("breq",   "1111 00kk kkkk k001", &[REL]),
+    ("brmi",   "1111 00kk kkkk k010", &[REL]),
+    ("brvs",   "1111 00kk kkkk k011", &[REL]),
+    ("brlt",   "1111 00kk kkkk k100", &[REL]),
+    ("brhs",   "1111 00kk kkkk k101", &[REL]),
+    ("brts",   "1111 00kk kkkk k110", &[REL]),
+    ("brie",   "1111 00kk kkkk k111", &[REL]),
+    ("brcc",   "1111 01kk kkkk k000", &[REL]),
+    ("brne",   "1111 01kk kkkk k001", &[REL]),
+    ("brpl",   "1111 01kk kkkk k010", &[REL]),
+    ("brvc",   "1111 01kk kkkk k011", &[REL]),
+    ("brge",   "1111 01kk kkkk k100", &[REL]),
+    ("brhc",   "1111 01kk kkkk k101", &[REL]),
+    ("brtc",   "1111 01kk kkkk k110", &[REL]),
+    ("brid",   "1111 01kk kkkk k111", &[REL]),
+    ("brlo",   "1111 00kk kkkk k000", &[REL]),
+    ("brsh",   "1111 01kk kkkk k000", &[REL]),
+    ("brbs",   "1111 00kk kkkk ksss", &[Bit { field: 's' }, REL]),
+    ("brbc",   "1111 01kk kkkk ksss", &[Bit { field: 's' }, REL]),
+    ("rjmp",   "1100 kkkk kkkk kkkk", &[REL]),
+    ("rcall",  "1101 kkkk kkkk kkkk", &[REL]),
+    ("jmp",    "1001 010k kkkk 110k kkkk kkkk kkkk kkkk", &[ABS]),
+    ("call",   "1001 010k kkkk 111k kkkk kkkk kkkk kkkk", &[ABS]),
+
+    ("lds",    "1001 000d dddd 0000 kkkk kkkk kkkk kkkk", &[RD, DATA]),
+    ("sts",    "1001 001r rrrr 0000 kkkk kkkk kkkk kkkk", &[DATA, RR]),
+    ("ld",     "1001 000d dddd 1100", &[RD, X]),
+    ("ld",     "1001 000d dddd 1101", &[RD, X_INC]),
+    ("ld",     "1001 000d dddd 1110", &[RD, X_DEC]),
+    ("ld",     "1000 000d dddd 1000", &[RD, Y]),
+    ("ld",     "1001 000d dddd 1001", &[RD, Y_INC]),
+    ("ld",     "1001 000d dddd 1010", &[RD, Y_DEC]),
+    ("ld",     "1000 000d dddd 0000", &[RD, Z]),
+    ("ld",     "1001 000d dddd 0001", &[RD, Z_INC]),
+    ("ld",     "1001 000d dddd 0010", &[RD, Z_DEC]),
+    ("ldd",    "10q0 qq0d dddd 1qqq", &[RD, Y_DISP]),
+    ("ldd",    "10q0 qq0d dddd 0qqq", &[RD, Z_DISP]),
+    ("st",     "1001 001r rrrr 1100", &[X, RR]),
+    ("st",     "1001 001r rrrr 1101", &[X_INC, RR]),
+    ("st",     "1001 001r rrrr 1110", &[X_DEC, RR]),
+    ("st",     "1000 001r rrrr 1000", &[Y, RR]),
+    ("st",     "1001 001r rrrr 1001", &[Y_INC, RR]),
+    ("st",     "1001 001r rrrr 1010", &[Y_DEC, RR]),
+    ("st",     "1000 001r rrrr 0000", &[Z, RR]),
+    ("st",     "1001 001r rrrr 0001", &[Z_INC, RR]),
+    ("st",     "1001 001r rrrr 0010", &[Z_DEC, RR]),
+    ("std",    "10q0 qq1r rrrr 1qqq", &[Y_DISP, RR]),
+    ("std",    "10q0 qq1r rrrr 0qqq", &[Z_DISP, RR]),
+    ("lpm",    "1001 000d dddd 0100", &[RD, Z]),
+    ("lpm",    "1001 000d dddd 0101", &[RD, Z_INC]),
+    ("elpm",   "1001 000d dddd 0110", &[RD, Z]),
+    ("elpm",   "1001 000d dddd 0111", &[RD, Z_INC]),
+];
+
+/// One entry of the opcode table with precomputed match masks.
+#[derive(Clone, Debug)]
+pub struct Opcode {
+    name: &'static str,
+    bits: Vec<char>,
+    ops: &'static [OpKind],
+    mask: u16,
+    value: u16,
+}
+
+impl Opcode {
+    fn new(name: &'static str, pattern: &'static str, ops: &'static [OpKind]) -> Self {
+        let bits: Vec<char> = pattern.chars().filter(|c| !c.is_whitespace()).collect();
+        assert!(bits.len() == 16 || bits.len() == 32);
+        let mut mask = 0;
+        let mut value = 0;
+        for (i, c) in bits.iter().take(16).enumerate() {
+            let bit = 1 << (15 - i);
+            match c {
+                '0' => mask |= bit,
+                '1' => {
+                    mask |= bit;
+                    value |= bit;
+                }
+                _ => (),
+            }
+        }
+        Self {
+            name,
+            bits,
+            ops,
+            mask,
+            value,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn ops(&self) -> &'static [OpKind] {
+        self.ops
+    }
+
+    /// Size of the instruction, in 16 bit words.
+    pub fn size_words(&self) -> u32 {
+        self.bits.len() as u32 / 16
+    }
+
+    fn matches(&self, word: u16) -> bool {
+        word & self.mask == self.value
+    }
+
+    /// Number of bits of the field `letter`.
+    fn field_width(&self, letter: char) -> u32 {
+        self.bits.iter().filter(|c| **c == letter).count() as u32
+    }
+
+    /// Extract the field `letter` from the (one or two word) instruction code.
+    fn field(&self, code: u32, letter: char) -> u32 {
+        let n = self.bits.len();
+        let mut v = 0;
+        for (i, c) in self.bits.iter().enumerate() {
+            if *c == letter {
+                v = (v << 1) | ((code >> (n - 1 - i)) & 1);
+            }
+        }
+        v
+    }
+
+    /// Extract the field `letter` and sign extend it.
+    fn field_signed(&self, code: u32, letter: char) -> i32 {
+        let width = self.field_width(letter);
+        let v = self.field(code, letter);
+        ((v << (32 - width)) as i32) >> (32 - width)
+    }
+
+    /// Convert the operand field of an instruction code to a typed operand.
+    fn decode_op(&self, kind: &OpKind, code: u32) -> Operand {
+        match *kind {
+            Reg { field, base, scale } => {
+                let reg = (base as u32 + self.field(code, field) * scale as u32) as u8;
+                if scale == 2 {
+                    Operand::RegPair(reg)
+                } else {
+                    Operand::Reg(reg)
+                }
+            }
+            Imm { field } => Operand::Imm(self.field(code, field) as i32),
+            Bit { field } => Operand::Bit(self.field(code, field) as u8),
+            Io { field } => Operand::Io(self.field(code, field) as u8),
+            Data { field } => Operand::Data(self.field(code, field)),
+            Rel { field } => Operand::RelOffset(self.field_signed(code, field) * 2),
+            Abs { field } => Operand::FlashAddr(self.field(code, field) * 2),
+            Disp { field, ptr } => Operand::Ptr(ptr, PtrMode::Disp(self.field(code, field) as u8)),
+            Ptr { ptr, mode } => Operand::Ptr(ptr, mode),
+        }
+    }
+
+    /// Parse an operand in avr-objdump or assembler notation.
+    fn parse_op(&self, kind: &OpKind, op: &str) -> Option<Operand> {
+        let op = op.trim();
+        match *kind {
+            Reg { scale, .. } => {
+                let reg = op.strip_prefix('r')?.parse::<u8>().ok()?;
+                if reg > 31 {
+                    None
+                } else if scale == 2 {
+                    Some(Operand::RegPair(reg))
+                } else {
+                    Some(Operand::Reg(reg))
+                }
+            }
+            Imm { .. } => match parse_num(op) {
+                Some(v) => Some(Operand::Imm(v.try_into().ok()?)),
+                None => Some(Operand::Expr(Expr::parse(op)?)),
+            },
+            Bit { .. } => Some(Operand::Bit(parse_num(op)?.try_into().ok()?)),
+            Io { .. } => Some(Operand::Io(parse_num(op)?.try_into().ok()?)),
+            Data { .. } => Some(Operand::Data(parse_num(op)?.try_into().ok()?)),
+            Rel { .. } => {
+                if let Some(rel) = op.strip_prefix('.') {
+                    Some(Operand::RelOffset(parse_num(rel)?.try_into().ok()?))
+                } else if is_label(op) {
+                    Some(Operand::label_op(op))
+                } else {
+                    None
+                }
+            }
+            Abs { .. } => match parse_num(op) {
+                Some(v) => Some(Operand::FlashAddr(v.try_into().ok()?)),
+                None if is_label(op) => Some(Operand::label_op(op)),
+                None => None,
+            },
+            Disp { ptr, .. } => {
+                let q = op.strip_prefix(&ptr.to_string())?.strip_prefix('+')?;
+                Some(Operand::Ptr(
+                    ptr,
+                    PtrMode::Disp(parse_num(q)?.try_into().ok()?),
+                ))
+            }
+            Ptr { ptr, mode } => {
+                let operand = Operand::Ptr(ptr, mode);
+                if operand.to_string() == op {
+                    Some(operand)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Insert `value` into the field `letter` of the instruction code.
+    fn set_field(&self, code: &mut u32, letter: char, mut value: u32) {
+        let n = self.bits.len();
+        for (i, c) in self.bits.iter().enumerate().rev() {
+            if *c == letter {
+                let bit = 1 << (n - 1 - i);
+                if value & 1 == 0 {
+                    *code &= !bit;
+                } else {
+                    *code |= bit;
+                }
+                value >>= 1;
+            }
+        }
+    }
+
+    /// Check that `value` fits into the unsigned field `letter`.
+    fn check_unsigned(&self, letter: char, value: i64, op: &Operand) -> ah::Result<u32> {
+        let width = self.field_width(letter);
+        if value < 0 || value >= 1 << width {
+            return Err(err!(
+                "Operand '{op}' = {value} does not fit into {width} bits."
+            ));
+        }
+        Ok(value as u32)
+    }
+
+    /// Check that `value` fits into the signed field `letter`.
+    fn check_signed(&self, letter: char, value: i64, op: &Operand) -> ah::Result<u32> {
+        let width = self.field_width(letter);
+        if value < -(1 << (width - 1)) || value >= 1 << (width - 1) {
+            return Err(err!("Operand '{op}' = {value} is out of range."));
+        }
+        Ok((value as u32) & ((1 << width) - 1))
+    }
+
+    /// Encode one operand into the instruction code.
+    fn encode_op(
+        &self,
+        code: &mut u32,
+        kind: &OpKind,
+        op: &Operand,
+        addr: u32,
+        env: &EncodeEnv<'_>,
+    ) -> ah::Result<()> {
+        match (*kind, op) {
+            (Reg { field, base, scale }, Operand::Reg(reg))
+            | (Reg { field, base, scale }, Operand::RegPair(reg))
+                if (scale == 2) == matches!(op, Operand::RegPair(_)) =>
+            {
+                let (reg, base, scale) = (*reg as u32, base as u32, scale as u32);
+                if reg < base || (reg - base) % scale != 0 {
+                    return Err(err!("Register '{op}' is not allowed here."));
+                }
+                let v = self.check_unsigned(field, ((reg - base) / scale).into(), op)?;
+                self.set_field(code, field, v);
+            }
+            (Imm { field }, Operand::Imm(_) | Operand::Expr(_)) => {
+                let v = match op {
+                    Operand::Expr(expr) => expr.eval((env.resolve)(&expr.label)?),
+                    _ => op.imm().unwrap().into(),
+                };
+                let width = self.field_width(field);
+                // Negative immediates are encoded as two's complement.
+                let v = if v < 0 && v >= -(1 << (width - 1)) {
+                    v + (1 << width)
+                } else {
+                    v
+                };
+                let v = self.check_unsigned(field, v, op)?;
+                self.set_field(code, field, v);
+            }
+            (Bit { field }, Operand::Bit(b)) => {
+                let v = self.check_unsigned(field, (*b).into(), op)?;
+                self.set_field(code, field, v);
+            }
+            (Io { field }, Operand::Io(a)) => {
+                let v = self.check_unsigned(field, (*a).into(), op)?;
+                self.set_field(code, field, v);
+            }
+            (Data { field }, Operand::Data(a)) => {
+                let v = self.check_unsigned(field, (*a).into(), op)?;
+                self.set_field(code, field, v);
+            }
+            (Rel { field }, Operand::RelOffset(_) | Operand::Label(_)) => {
+                let offset = match op {
+                    Operand::RelOffset(rel) => *rel as i64 / 2,
+                    _ => (env.resolve)(op.label().unwrap())? as i64 - (addr as i64 + 1),
+                };
+                let width = self.field_width(field);
+                let flash_words = env.flash_size as i64 / 2;
+                let offset = if (offset < -(1 << (width - 1)) || offset >= 1 << (width - 1))
+                    && flash_words > 0
+                    && flash_words <= 1 << width
+                {
+                    // The whole flash is reachable by wrapping around.
+                    let o = offset.rem_euclid(flash_words);
+                    if o >= flash_words / 2 {
+                        o - flash_words
+                    } else {
+                        o
+                    }
+                } else {
+                    offset
+                };
+                let v = self
+                    .check_signed(field, offset, op)
+                    .map_err(|_| err!("Jump target '{op}' is out of range ({offset} words)."))?;
+                self.set_field(code, field, v);
+            }
+            (Abs { field }, Operand::FlashAddr(_) | Operand::Label(_)) => {
+                let v = match op {
+                    Operand::FlashAddr(a) => *a / 2,
+                    _ => (env.resolve)(op.label().unwrap())?,
+                };
+                let v = self.check_unsigned(field, v.into(), op)?;
+                self.set_field(code, field, v);
+            }
+            (Disp { field, ptr }, Operand::Ptr(p, PtrMode::Disp(q))) if *p == ptr => {
+                let v = self.check_unsigned(field, (*q).into(), op)?;
+                self.set_field(code, field, v);
+            }
+            (Ptr { ptr, mode }, Operand::Ptr(p, m)) if *p == ptr && *m == mode => (),
+            (_, op) => {
+                return Err(err!("Operand '{op}' is not allowed here."));
+            }
+        }
+        Ok(())
+    }
+
+    /// Encode the instruction with the operands `ops`.
+    fn encode(&self, ops: &[Operand], addr: u32, env: &EncodeEnv<'_>) -> ah::Result<Vec<u16>> {
+        let n = self.bits.len();
+        let mut code = 0;
+        for (i, c) in self.bits.iter().enumerate() {
+            if *c == '1' {
+                code |= 1 << (n - 1 - i);
+            }
+        }
+        for (kind, op) in self.ops.iter().zip(ops.iter()) {
+            self.encode_op(&mut code, kind, op, addr, env)?;
+        }
+        if self.size_words() == 2 {
+            Ok(vec![(code >> 16) as u16, code as u16])
+        } else {
+            Ok(vec![code as u16])
+        }
+    }
+}
+
+static OPCODES: LazyLock<Vec<Opcode>> = LazyLock::new(|| {
+    OPCODE_TABLE
+        .iter()
+        .map(|(name, pattern, ops)| Opcode::new(name, pattern, ops))
+        .collect()
+});
+
+/// A decoded instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodedInsn {
+    pub name: String,
+    pub ops: Vec<Operand>,
+    /// Size of the instruction, in 16 bit words.
+    pub size_words: u32,
+}
+
+/// Decode the instruction at the start of `words`.
+///
+/// `may_be_long` tells whether the second word belongs to the same
+/// instruction stream and may be consumed by a two-word instruction.
+/// Words that are not valid instructions are decoded as `.word` directive.
+pub fn decode_insn(words: &[u16], may_be_long: bool) -> DecodedInsn {
+    let w0 = words[0];
+    for opcode in OPCODES.iter() {
+        if !opcode.matches(w0) {
+            continue;
+        }
+        let code = if opcode.size_words() == 2 {
+            if !may_be_long || words.len() < 2 {
+                continue;
+            }
+            ((w0 as u32) << 16) | words[1] as u32
+        } else {
+            w0 as u32
+        };
+        return DecodedInsn {
+            name: opcode.name().to_string(),
+            ops: opcode
+                .ops()
+                .iter()
+                .map(|kind| opcode.decode_op(kind, code))
+                .collect(),
+            size_words: opcode.size_words(),
+        };
+    }
+    DecodedInsn {
+        name: ".word".to_string(),
+        ops: vec![Operand::Imm(w0.into())],
+        size_words: 1,
+    }
+}
+
+/// Environment of the instruction encoder.
+pub struct EncodeEnv<'a> {
+    /// Flash size, in bytes.
+    pub flash_size: u32,
+    /// Resolve a flash label to its word address.
+    pub resolve: &'a dyn Fn(&str) -> ah::Result<u32>,
+}
+
+/// Parse a decimal or hexadecimal number.
+pub fn parse_num(s: &str) -> Option<i64> {
+    let s = s.trim();
+    let (neg, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let v = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else {
+        s.parse::<i64>().ok()?
+    };
+    Some(if neg { -v } else { v })
+}
+
+/// Get the size of the instruction `name`, in 16 bit words.
+pub fn insn_size_words(name: &str) -> Option<u32> {
+    if name == ".word" {
+        return Some(1);
+    }
+    OPCODES
+        .iter()
+        .find(|opcode| opcode.name() == name)
+        .map(|opcode| opcode.size_words())
+}
+
+/// Parse the operands of the instruction `name` given in
+/// avr-objdump or assembler notation.
+pub fn parse_insn_ops(name: &str, ops: &[&str]) -> ah::Result<Vec<Operand>> {
+    if name == ".word" {
+        if let [op] = ops {
+            if let Some(v) = parse_num(op).filter(|v| (0..=0xFFFF).contains(v)) {
+                return Ok(vec![Operand::Imm(v as i32)]);
+            }
+            if let Some(expr) = Expr::parse_word(op) {
+                return Ok(vec![Operand::Expr(expr)]);
+            }
+        }
+        return Err(err!("Invalid .word operands '{}'.", ops.join(", ")));
+    }
+    for opcode in OPCODES
+        .iter()
+        .filter(|opcode| opcode.name() == name && opcode.ops().len() == ops.len())
+    {
+        let parsed: Option<Vec<Operand>> = opcode
+            .ops()
+            .iter()
+            .zip(ops.iter())
+            .map(|(kind, op)| opcode.parse_op(kind, op))
+            .collect();
+        if let Some(parsed) = parsed {
+            return Ok(parsed);
+        }
+    }
+    Err(err!("Invalid instruction '{name} {}'.", ops.join(", ")))
+}
+
+/// Encode an instruction.
+///
+/// `addr` is the word address of the instruction.
+pub fn encode_insn(
+    name: &str,
+    ops: &[Operand],
+    addr: u32,
+    env: &EncodeEnv<'_>,
+) -> ah::Result<Vec<u16>> {
+    if name == ".word" {
+        match ops {
+            [Operand::Imm(v)] => {
+                if let Ok(v) = u16::try_from(*v) {
+                    return Ok(vec![v]);
+                }
+            }
+            [Operand::Expr(expr)] if expr.func == ExprFunc::Word => {
+                return Ok(vec![expr.eval((env.resolve)(&expr.label)?) as u16]);
+            }
+            _ => (),
+        }
+        return Err(err!(
+            ".word needs exactly one 16 bit immediate or address operand."
+        ));
+    }
+
+    let mut error = None;
+    for opcode in OPCODES
+        .iter()
+        .filter(|opcode| opcode.name() == name && opcode.ops().len() == ops.len())
+    {
+        match opcode.encode(ops, addr, env) {
+            Ok(code) => return Ok(code),
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(error.unwrap_or_else(|| err!("Unknown instruction '{name}' with {} operands.", ops.len())))
+}
+
+/// Check that the instruction exists and that its operands are valid.
+/// Labels are not resolved.
+pub fn check_insn(name: &str, ops: &[Operand]) -> ah::Result<()> {
+    let resolve = |_: &str| Ok(0);
+    let env = EncodeEnv {
+        flash_size: 0,
+        resolve: &resolve,
+    };
+    encode_insn(name, ops, 0, &env).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let resolve = |label: &str| Err(err!("Unexpected label '{label}'."));
+        let env = EncodeEnv {
+            flash_size: 0,
+            resolve: &resolve,
+        };
+        for w0 in 0..=0xFFFF {
+            let words = [w0, 0x1234];
+            let insn = decode_insn(&words, true);
+            if insn.name == ".word" {
+                continue;
+            }
+            let code = encode_insn(&insn.name, &insn.ops, 0x100, &env)
+                .unwrap_or_else(|e| panic!("{w0:04X} {}: {e}", insn.name));
+            assert_eq!(code, words[..insn.size_words as usize], "{}", insn.name);
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let insn = decode_insn(&[0x940C, 0x0034], true);
+        assert_eq!(insn.name, "jmp");
+        assert_eq!(insn.ops, [Operand::FlashAddr(0x68)]);
+        assert_eq!(insn.size_words, 2);
+
+        // A two word instruction at the end of the stream.
+        let insn = decode_insn(&[0x940C, 0x0034], false);
+        assert_eq!(insn.name, ".word");
+        assert_eq!(insn.ops, [Operand::Imm(0x940C)]);
+
+        let insn = decode_insn(&[0xCFFF], false);
+        assert_eq!(insn.name, "rjmp");
+        assert_eq!(insn.ops, [Operand::RelOffset(-2)]);
+
+        let insn = decode_insn(&[0x818D], false);
+        assert_eq!(insn.name, "ldd");
+        assert_eq!(
+            insn.ops,
+            [Operand::Reg(24), Operand::Ptr(PtrReg::Y, PtrMode::Disp(5))]
+        );
+    }
+
+    #[test]
+    fn test_parse_ops() {
+        let ops = parse_insn_ops("ldd", &["r24", "Y+5"]).unwrap();
+        assert_eq!(
+            ops,
+            [Operand::Reg(24), Operand::Ptr(PtrReg::Y, PtrMode::Disp(5))]
+        );
+        let ops = parse_insn_ops("ldi", &["r15", "0x01"]).unwrap();
+        assert!(check_insn("ldi", &ops).is_err());
+        assert!(parse_insn_ops("ldi", &["r16"]).is_err());
+        assert!(parse_insn_ops(".word", &["0x10000"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_num() {
+        assert_eq!(parse_num("0"), Some(0));
+        assert_eq!(parse_num(" 42 "), Some(42));
+        assert_eq!(parse_num("+7"), Some(7));
+        assert_eq!(parse_num("-7"), Some(-7));
+        assert_eq!(parse_num("0x1F"), Some(0x1F));
+        assert_eq!(parse_num("0XfF"), Some(0xFF));
+        assert_eq!(parse_num("-0x10"), Some(-0x10));
+        assert_eq!(parse_num(""), None);
+        assert_eq!(parse_num("0x"), None);
+        assert_eq!(parse_num("12a"), None);
+        assert_eq!(parse_num("r24"), None);
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -0,0 +1,335 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+/// Assembler dialect used to format operands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dialect {
+    /// avra (and avr-objdump) syntax.
+    Avra,
+    /// GNU avr-as syntax.
+    Gnu,
+}
+
+/// Pointer register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PtrReg {
+    X,
+    Y,
+    Z,
+}
+
+impl PtrReg {
+    /// Number of the low register of the pointer register pair.
+    #[allow(unused)]
+    pub fn lo_reg(&self) -> u8 {
+        match self {
+            PtrReg::X => 26,
+            PtrReg::Y => 28,
+            PtrReg::Z => 30,
+        }
+    }
+}
+
+impl std::fmt::Display for PtrReg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            PtrReg::X => write!(f, "X"),
+            PtrReg::Y => write!(f, "Y"),
+            PtrReg::Z => write!(f, "Z"),
+        }
+    }
+}
+
+/// Addressing mode of a pointer register operand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PtrMode {
+    /// X
+    Plain,
+    /// X+
+    PostInc,
+    /// -X
+    PreDec,
+    /// Y+q
+    Disp(u8),
+}
+
+/// Byte selector of an expression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ExprFunc {
+    /// Bits 0-7.
+    Lo8,
+    /// Bits 8-15.
+    Hi8,
+    /// Bits 16-23.
+    Hh8,
+    /// Bits 0-15, as used by `.word`.
+    Word,
+}
+
+impl ExprFunc {
+    pub fn shift(&self) -> u32 {
+        match self {
+            ExprFunc::Lo8 | ExprFunc::Word => 0,
+            ExprFunc::Hi8 => 8,
+            ExprFunc::Hh8 => 16,
+        }
+    }
+
+    pub fn mask(&self) -> i64 {
+        match self {
+            ExprFunc::Word => 0xFFFF,
+            _ => 0xFF,
+        }
+    }
+
+    fn name(&self, dialect: Dialect) -> &'static str {
+        match (self, dialect) {
+            (ExprFunc::Lo8, Dialect::Avra) => "low",
+            (ExprFunc::Hi8, Dialect::Avra) => "high",
+            (ExprFunc::Hh8, Dialect::Avra) => "byte3",
+            (ExprFunc::Lo8, Dialect::Gnu) => "lo8",
+            (ExprFunc::Hi8, Dialect::Gnu) => "hi8",
+            (ExprFunc::Hh8, Dialect::Gnu) => "hh8",
+            (ExprFunc::Word, _) => "",
+        }
+    }
+}
+
+/// Byte of the address of a flash label.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Expr {
+    pub func: ExprFunc,
+    pub label: String,
+    /// Use the program memory word address instead of the byte address.
+    pub pm: bool,
+    /// Use the negated address, as used by subi/sbci.
+    pub neg: bool,
+}
+
+impl Expr {
+    pub fn new(func: ExprFunc, label: &str, pm: bool) -> Self {
+        Self {
+            func,
+            label: label.to_string(),
+            pm,
+            neg: false,
+        }
+    }
+
+    pub fn negated(mut self) -> Self {
+        self.neg = !self.neg;
+        self
+    }
+
+    /// Evaluate the expression with the word address of the label.
+    pub fn eval(&self, label_word_addr: u32) -> i64 {
+        let addr = if self.pm {
+            label_word_addr as i64
+        } else {
+            label_word_addr as i64 * 2
+        };
+        let addr = if self.neg { -addr } else { addr };
+        (addr >> self.func.shift()) & self.func.mask()
+    }
+
+    pub fn to_asm(&self, dialect: Dialect) -> String {
+        let addr = match (dialect, self.pm) {
+            // avra labels are word addresses.
+            (Dialect::Avra, false) => format!("{} * 2", self.label),
+            (Dialect::Avra, true) => self.label.clone(),
+            // GNU labels are byte addresses.
+            (Dialect::Gnu, false) => self.label.clone(),
+            (Dialect::Gnu, true) => format!("pm({})", self.label),
+        };
+        let addr = if self.neg { format!("-({addr})") } else { addr };
+        match self.func {
+            ExprFunc::Word => addr,
+            func => format!("{}({addr})", func.name(dialect)),
+        }
+    }
+
+    /// Parse the address part of an expression: `label`, `-(label)`
+    /// and `pm(label)` (GNU) or `label * 2` (avra).
+    fn parse_addr(s: &str, avra: bool) -> Option<(&str, bool, bool)> {
+        let s = s.trim();
+        let (s, neg) = match s.strip_prefix("-(").and_then(|s| s.strip_suffix(')')) {
+            Some(s) => (s.trim(), true),
+            None => (s, false),
+        };
+        let (label, pm) = if avra {
+            match s.strip_suffix("* 2").or_else(|| s.strip_suffix("*2")) {
+                Some(label) => (label.trim(), false),
+                None => (s, true),
+            }
+        } else {
+            match s.strip_prefix("pm(").and_then(|s| s.strip_suffix(')')) {
+                Some(label) => (label.trim(), true),
+                None => (s, false),
+            }
+        };
+        is_label(label).then_some((label, pm, neg))
+    }
+
+    /// Parse an avra or GNU style expression.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let open = s.find('(')?;
+        let inner = s[open + 1..].strip_suffix(')')?;
+        let (func, avra) = match &s[..open] {
+            "low" => (ExprFunc::Lo8, true),
+            "high" => (ExprFunc::Hi8, true),
+            "byte3" => (ExprFunc::Hh8, true),
+            "lo8" => (ExprFunc::Lo8, false),
+            "hi8" => (ExprFunc::Hi8, false),
+            "hh8" => (ExprFunc::Hh8, false),
+            _ => return None,
+        };
+        let (label, pm, neg) = Self::parse_addr(inner, avra)?;
+        let expr = Self::new(func, label, pm);
+        Some(if neg { expr.negated() } else { expr })
+    }
+
+    /// Parse a GNU style `.word` expression.
+    pub fn parse_word(s: &str) -> Option<Self> {
+        let (label, pm, neg) = Self::parse_addr(s, false)?;
+        let expr = Self::new(ExprFunc::Word, label, pm);
+        Some(if neg { expr.negated() } else { expr })
+    }
+}
+
+/// Check whether `s` is a valid label name.
+pub fn is_label(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Typed instruction operand.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Operand {
+    /// Register r0-r31.
+    Reg(u8),
+    /// Register pair, named by its low register (movw, adiw, sbiw).
+    RegPair(u8),
+    /// Immediate value.
+    Imm(i32),
+    /// Bit number.
+    Bit(u8),
+    /// I/O space address.
+    Io(u8),
+    /// Data space address.
+    Data(u32),
+    /// Flash label.
+    Label(String),
+    /// Pointer register with addressing mode.
+    Ptr(PtrReg, PtrMode),
+    /// Assembler expression.
+    Expr(Expr),
+    /// Relative flash offset in bytes, as disassembled (.+N).
+    /// Replaced by a label after disassembly.
+    RelOffset(i32),
+    /// Absolute flash byte address, as disassembled.
+    /// Replaced by a label after disassembly.
+    FlashAddr(u32),
+}
+
+impl Operand {
+    pub fn label_op(label: &str) -> Self {
+        Operand::Label(label.to_string())
+    }
+
+    /// Get the register number of a register operand.
+    pub fn reg(&self) -> Option<u8> {
+        match self {
+            Operand::Reg(r) => Some(*r),
+            _ => None,
+        }
+    }
+
+    /// Get the low register number of a register pair operand.
+    #[allow(unused)]
+    pub fn reg_pair(&self) -> Option<u8> {
+        match self {
+            Operand::RegPair(r) => Some(*r),
+            _ => None,
+        }
+    }
+
+    pub fn imm(&self) -> Option<i32> {
+        match self {
+            Operand::Imm(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    #[allow(unused)]
+    pub fn bit(&self) -> Option<u8> {
+        match self {
+            Operand::Bit(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn io(&self) -> Option<u8> {
+        match self {
+            Operand::Io(a) => Some(*a),
+            _ => None,
+        }
+    }
+
+    pub fn data(&self) -> Option<u32> {
+        match self {
+            Operand::Data(a) => Some(*a),
+            _ => None,
+        }
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        match self {
+            Operand::Label(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    #[allow(unused)]
+    pub fn ptr(&self) -> Option<(PtrReg, PtrMode)> {
+        match self {
+            Operand::Ptr(p, m) => Some((*p, *m)),
+            _ => None,
+        }
+    }
+
+    pub fn to_asm(&self, dialect: Dialect) -> String {
+        match self {
+            Operand::Expr(expr) => expr.to_asm(dialect),
+            op => op.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Operand::Reg(r) | Operand::RegPair(r) => write!(f, "r{r}"),
+            Operand::Imm(v) if *v < 0 => write!(f, "-0x{:02X}", -(*v as i64)),
+            Operand::Imm(v) => write!(f, "0x{v:02X}"),
+            Operand::Bit(b) => write!(f, "{b}"),
+            Operand::Io(a) => write!(f, "0x{a:02x}"),
+            Operand::Data(a) => write!(f, "0x{a:04X}"),
+            Operand::Label(l) => write!(f, "{l}"),
+            Operand::Ptr(p, PtrMode::Plain) => write!(f, "{p}"),
+            Operand::Ptr(p, PtrMode::PostInc) => write!(f, "{p}+"),
+            Operand::Ptr(p, PtrMode::PreDec) => write!(f, "-{p}"),
+            Operand::Ptr(p, PtrMode::Disp(q)) => write!(f, "{p}+{q}"),
+            Operand::Expr(expr) => write!(f, "{}", expr.to_asm(Dialect::Avra)),
+            Operand::RelOffset(o) => write!(f, ".{o:+}"),
+            Operand::FlashAddr(a) => write!(f, "0x{a:x}"),
+        }
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -4,80 +4,399 @@
 
 use crate::program::Program;
 use anyhow::{self as ah, Context as _, format_err as err};
-use std::collections::BTreeMap;
+use params::{ParamDesc, ParamType, Params};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+};
+
+mod params;
+
+/// Registry entry of a patch step.
+struct StepDesc {
+    step: Steps,
+    name: &'static str,
+    /// Steps that must be requested together with this step.
+    /// They run before this step.
+    requires: &'static [&'static str],
+    /// Steps that must not be requested together with this step.
+    conflicts: &'static [&'static str],
+    /// Steps that run before this step, if they are requested.
+    after: &'static [&'static str],
+    params: &'static [ParamDesc],
+}
 
 macro_rules! define_patchers {
+    (@run $module:ident, $program:ident, $params:ident) => {
+        $module::run($program).await
+    };
+    (@run $module:ident, $program:ident, $params:ident $(, $pname:literal)+) => {
+        $module::run($program, $params).await
+    };
     (
         $(
             {
                 module: $module:ident,
                 name: $name:literal,
-                prio: $prio:literal,
+                $( requires: [ $( $requires:literal ),* $(,)? ], )?
+                $( conflicts: [ $( $conflicts:literal ),* $(,)? ], )?
+                $( after: [ $( $after:literal ),* $(,)? ], )?
+                $(
+                    params: [
+                        $(
+                            {
+                                name: $pname:literal,
+                                ty: $pty:ident,
+                                default: $pdefault:expr,
+                                help: $phelp:literal,
+                            }
+                        ),* $(,)?
+                    ],
+                )?
             }
         ),*
     ) => {
-        paste::paste! {
+        $(
+            mod $module;
+        )*
+
+        #[allow(non_camel_case_types)]
+        #[derive(Clone, Copy)]
+        enum Steps {
+            $(
+                $module,
+            )*
+        }
+
+        /// All patch steps.
+        /// Steps that don't depend on each other run in this order.
+        const STEPS: &[StepDesc] = &[
             $(
-                mod $module;
+                StepDesc {
+                    step: Steps::$module,
+                    name: $name,
+                    requires: &[$($($requires),*)?],
+                    conflicts: &[$($($conflicts),*)?],
+                    after: &[$($($after),*)?],
+                    params: &[
+                        $($(
+                            ParamDesc {
+                                name: $pname,
+                                ty: ParamType::$pty,
+                                default: $pdefault,
+                                help: $phelp,
+                            },
+                        )*)?
+                    ],
+                },
             )*
+        ];
 
-            #[allow(non_camel_case_types)]
-            enum Steps {
+        async fn run_step(program: &mut Program, step: Steps, params: &Params) -> ah::Result<()> {
+            match step {
                 $(
-                    $module,
+                    Steps::$module => {
+                        define_patchers!(@run $module, program, params $($(, $pname)*)?)
+                            .context($name)
+                    }
                 )*
             }
+        }
+    }
+}
 
-            $(
-                #[allow(non_upper_case_globals)]
-                const [<PRIO_ $module>]: i32 = $prio;
-            )*
+fn find_step(name: &str) -> Option<usize> {
+    STEPS.iter().position(|s| s.name == name)
+}
 
-            async fn run_step(program: &mut Program, step: &Steps) -> ah::Result<()> {
-                match step {
-                    $(
-                        Steps::$module => $module::run(program)
-                            .await
-                            .context($name),
-                    )*
-                }
+/// Parse the parameters of a step, for the unit tests of the steps.
+#[cfg(test)]
+fn test_params(name: &str, text: Option<&str>) -> Params {
+    Params::parse(STEPS[find_step(name).unwrap()].params, text).unwrap()
+}
+
+/// Help text with all patch steps and their parameters.
+pub fn steps_help() -> String {
+    let mut help = String::from("Patch step to run. May be given multiple times.\n\nSteps:");
+    for step in STEPS {
+        write!(help, "\n  {}", step.name).unwrap();
+        for p in step.params {
+            let param = format!("{}={}", p.name, p.ty.name());
+            write!(help, "\n      {param:<16} {}", p.help).unwrap();
+            if let Some(default) = p.default {
+                write!(help, " (default: {default})").unwrap();
             }
+        }
+        if !step.requires.is_empty() {
+            write!(help, "\n      Requires: {}", step.requires.join(", ")).unwrap();
+        }
+        if !step.conflicts.is_empty() {
+            write!(
+                help,
+                "\n      Conflicts with: {}",
+                step.conflicts.join(", ")
+            )
+            .unwrap();
+        }
+        if !step.after.is_empty() {
+            write!(help, "\n      After: {}", step.after.join(", ")).unwrap();
+        }
+    }
+    help
+}
 
-            pub async fn patch_program(program: &mut Program, steps: &[String]) -> ah::Result<()> {
-                let mut active_steps = BTreeMap::new();
+/// Sort the requested steps (indices into `steps`) topologically,
+/// so that every step runs after the steps it requires or is ordered after.
+fn order_steps(steps: &[StepDesc], requested: &[usize]) -> ah::Result<Vec<usize>> {
+    let find = |name: &str| steps.iter().position(|s| s.name == name);
+    for step in steps {
+        for name in step.requires.iter().chain(step.conflicts).chain(step.after) {
+            if find(name).is_none() {
+                return Err(err!(
+                    "Step '{}' refers to the unknown step '{name}'.",
+                    step.name
+                ));
+            }
+        }
+    }
 
-                for step in steps {
-                    match &step[..] {
-                        $(
-                            $name => {
-                                active_steps.insert([<PRIO_ $module>], Steps::$module);
-                            }
-                        )*
-                        step => {
-                            return Err(err!("Unknown optimization step: {step}"));
-                        }
-                    }
-                }
+    // The predecessors of each requested step.
+    let mut preds: BTreeMap<usize, BTreeSet<usize>> =
+        requested.iter().map(|&i| (i, BTreeSet::new())).collect();
+    for &i in requested {
+        let step = &steps[i];
+        for name in step.requires {
+            if !requested.contains(&find(name).unwrap()) {
+                return Err(err!("Step '{}' requires the step '{name}'.", step.name));
+            }
+        }
+        for name in step.conflicts {
+            if requested.contains(&find(name).unwrap()) {
+                return Err(err!(
+                    "Step '{}' conflicts with the step '{name}'.",
+                    step.name
+                ));
+            }
+        }
+        for name in step.requires.iter().chain(step.after) {
+            let pred = find(name).unwrap();
+            if requested.contains(&pred) {
+                preds.get_mut(&i).unwrap().insert(pred);
+            }
+        }
+    }
+
+    // Take the first step in registry order that has no pending predecessors.
+    let mut order = vec![];
+    while let Some(next) = preds.iter().find(|(_, p)| p.is_empty()).map(|(&i, _)| i) {
+        preds.remove(&next);
+        for p in preds.values_mut() {
+            p.remove(&next);
+        }
+        order.push(next);
+    }
+    if !preds.is_empty() {
+        let names: Vec<&str> = preds.keys().map(|&i| steps[i].name).collect();
+        return Err(err!(
+            "Cyclic ordering dependency between the steps: {}",
+            names.join(", ")
+        ));
+    }
+    Ok(order)
+}
 
-                while let Some((_, step)) = active_steps.pop_first() {
-                    run_step(program, &step).await?;
-                }
+pub async fn patch_program(program: &mut Program, steps: &[String]) -> ah::Result<()> {
+    let mut requested = vec![];
+    let mut params = BTreeMap::new();
 
-                Ok(())
-            }
+    for step in steps {
+        // Step parameters are given as name:key=value,key=value
+        let (name, step_params) = match step.split_once(':') {
+            Some((name, params)) => (name, Some(params)),
+            None => (&step[..], None),
+        };
+        let Some(i) = find_step(name) else {
+            return Err(err!("Unknown optimization step: {step}"));
+        };
+        let step_params = Params::parse(STEPS[i].params, step_params)
+            .with_context(|| format!("Patch step '{step}'"))?;
+        if params.insert(i, step_params).is_some() {
+            return Err(err!("Patch step '{name}' is given more than once."));
         }
+        requested.push(i);
     }
+
+    for i in order_steps(STEPS, &requested)? {
+        run_step(program, STEPS[i].step, &params[&i]).await?;
+        program.check().context("Check patched program")?;
+    }
+
+    Ok(())
 }
 
 define_patchers! {
     {
         module: main_prologue,
         name: "main-prologue",
-        prio: 0,
     }, {
         module: bad_interrupt_exit,
         name: "bad-interrupt-exit",
-        prio: 1,
+        params: [
+            {
+                name: "target",
+                ty: Symbol,
+                default: Some("_exit"),
+                help: "Function that the unused interrupt vectors jump to.",
+            },
+        ],
+    }, {
+        module: truncate_vectors,
+        name: "truncate-vectors",
+        after: ["bad-interrupt-exit"],
+    }, {
+        module: noreturn,
+        name: "noreturn",
+        after: ["main-prologue"],
+    }, {
+        module: minimal_crt,
+        name: "minimal-crt",
+        after: ["bad-interrupt-exit", "noreturn"],
+    }, {
+        module: dead_functions,
+        name: "dead-functions",
+        after: ["bad-interrupt-exit", "truncate-vectors", "noreturn", "minimal-crt"],
+    }, {
+        module: tail_calls,
+        name: "tail-calls",
+        after: ["noreturn"],
+    }, {
+        module: jump_threading,
+        name: "jump-threading",
+        after: ["tail-calls"],
+    }, {
+        module: icf,
+        name: "icf",
+        after: ["tail-calls", "jump-threading"],
+    }, {
+        module: isr_slim,
+        name: "isr-slim",
+    }, {
+        module: callee_saved,
+        name: "callee-saved",
+        after: ["main-prologue", "isr-slim"],
+    }, {
+        module: io_access,
+        name: "io-access",
+        after: ["minimal-crt"],
+    }, {
+        module: rules,
+        name: "rules",
+        after: ["io-access"],
+        params: [
+            {
+                name: "file",
+                ty: Path,
+                default: None,
+                help: "Rule file.",
+            }, {
+                name: "passes",
+                ty: Uint,
+                default: Some("16"),
+                help: "Maximum number of rewrite passes over the program.",
+            },
+        ],
+    }, {
+        module: relax_calls,
+        name: "relax-calls",
+        // Runs last, because all other steps change the code size.
+        after: [
+            "main-prologue",
+            "bad-interrupt-exit",
+            "truncate-vectors",
+            "noreturn",
+            "minimal-crt",
+            "dead-functions",
+            "tail-calls",
+            "jump-threading",
+            "icf",
+            "isr-slim",
+            "callee-saved",
+            "io-access",
+            "rules",
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order_in(steps: &[StepDesc], names: &[&str]) -> ah::Result<Vec<&'static str>> {
+        let requested: Vec<usize> = names
+            .iter()
+            .map(|n| steps.iter().position(|s| s.name == *n).unwrap())
+            .collect();
+        Ok(order_steps(steps, &requested)?
+            .into_iter()
+            .map(|i| steps[i].name)
+            .collect())
+    }
+
+    fn order(names: &[&str]) -> ah::Result<Vec<&'static str>> {
+        order_in(STEPS, names)
+    }
+
+    const fn step(
+        name: &'static str,
+        requires: &'static [&'static str],
+        conflicts: &'static [&'static str],
+    ) -> StepDesc {
+        StepDesc {
+            step: Steps::noreturn,
+            name,
+            requires,
+            conflicts,
+            after: &[],
+            params: &[],
+        }
+    }
+
+    #[test]
+    fn test_order() {
+        assert_eq!(
+            order(&["relax-calls", "dead-functions", "noreturn"]).unwrap(),
+            ["noreturn", "dead-functions", "relax-calls"]
+        );
+        assert_eq!(
+            order(&["truncate-vectors", "bad-interrupt-exit"]).unwrap(),
+            ["bad-interrupt-exit", "truncate-vectors"]
+        );
+    }
+
+    #[test]
+    fn test_requires() {
+        let steps = [
+            step("a", &[], &["c"]),
+            step("b", &["a"], &[]),
+            step("c", &[], &[]),
+        ];
+        assert_eq!(order_in(&steps, &["b", "a"]).unwrap(), ["a", "b"]);
+        assert_eq!(
+            order_in(&steps, &["b"]).unwrap_err().to_string(),
+            "Step 'b' requires the step 'a'."
+        );
+        assert_eq!(
+            order_in(&steps, &["c", "a"]).unwrap_err().to_string(),
+            "Step 'a' conflicts with the step 'c'."
+        );
+    }
+
+    #[test]
+    fn test_help() {
+        let help = steps_help();
+        assert!(help.contains("\n  truncate-vectors\n      After: bad-interrupt-exit\n"));
+        assert!(help.contains("\n  tail-calls\n      After: noreturn\n"));
+        assert!(help.contains("\n      passes=UINT"));
     }
 }
 
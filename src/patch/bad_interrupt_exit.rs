@@ -2,20 +2,38 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 // Copyright (C) 2025 Michael Büsch <m@bues.ch>
 
-use crate::program::{InsnPatch, Program};
+use crate::{
+    operand::Operand,
+    patch::params::Params,
+    program::{InsnPatch, Program},
+};
 use anyhow::{self as ah, format_err as err};
 
-pub async fn run(program: &mut Program) -> ah::Result<()> {
+pub async fn run(program: &mut Program, params: &Params) -> ah::Result<()> {
+    let target = params.symbol("target");
     let Some(text) = program.section_text_mut() else {
         return Err(err!("Text section not found."));
     };
+    if target == "__bad_interrupt" {
+        return Err(err!("The target must not be __bad_interrupt."));
+    }
+    let target_exists = text
+        .parts()
+        .iter()
+        .any(|p| p.name() == target || p.insns().iter().any(|i| i.label() == Some(target)));
+    if !target_exists {
+        return Err(err!("Target '{target}' not found."));
+    }
 
-    // Make all unused interrupt vectors point to _exit instead of __bad_interrupt.
+    // Make all unused interrupt vectors point to the target instead of __bad_interrupt.
     if let Some(part) = text.find_part_mut("__vectors") {
         for insn in part.insns_mut() {
-            if insn.name() == "rjmp" && insn.ops().len() == 1 && insn.ops()[0] == "__bad_interrupt" {
+            if ["rjmp", "jmp"].contains(&insn.name())
+                && insn.ops().len() == 1
+                && insn.ops()[0].label() == Some("__bad_interrupt")
+            {
                 let mut pinsn = insn.clone();
-                pinsn.ops_mut()[0] = "_exit".to_string();
+                pinsn.ops_mut()[0] = Operand::label_op(target);
                 insn.set_patch(Some(InsnPatch::new(vec![pinsn])));
             }
         }
@@ -30,7 +48,59 @@ pub async fn run(program: &mut Program) -> ah::Result<()> {
         return Err(err!("__bad_interrupt not found."));
     }
 
+    program.set_unused_vector_target(Some(target.to_string()));
+
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        patch::test_params,
+        testutil::{block_on, part_asm, program},
+    };
+
+    const ASM: &str = "
+__vectors:
+    jmp main
+    jmp __bad_interrupt
+    jmp isr
+__bad_interrupt:
+    jmp __vectors
+isr:
+    reti
+main:
+    rjmp main
+_exit:
+    cli
+    l: rjmp l
+";
+
+    #[test]
+    fn test_redirect() {
+        let mut program = program("atmega328p", ASM);
+        let params = test_params("bad-interrupt-exit", Some("target=l"));
+        block_on(run(&mut program, &params)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        assert_eq!(
+            part_asm(&program, "__vectors"),
+            ["jmp main", "jmp l", "jmp isr"]
+        );
+        assert!(part_asm(&program, "__bad_interrupt").is_empty());
+        assert_eq!(program.unused_vector_target(), Some("l"));
+    }
+
+    #[test]
+    fn test_bad_target() {
+        let mut program = program("atmega328p", ASM);
+        let params = test_params("bad-interrupt-exit", Some("target=foo"));
+        let e = block_on(run(&mut program, &params)).unwrap_err();
+        assert_eq!(e.to_string(), "Target 'foo' not found.");
+        let params = test_params("bad-interrupt-exit", Some("target=__bad_interrupt"));
+        assert!(block_on(run(&mut program, &params)).is_err());
+        assert_eq!(program.unused_vector_target(), None);
+    }
+}
+
 // vim: ts=4 sw=4 expandtab
@@ -0,0 +1,233 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+use crate::{
+    abi::reg_is_callee_saved,
+    cfg::{Cfg, EdgeTarget},
+    insninfo::{Flow, RegSet},
+    operand::{Operand, PtrReg},
+    program::{Insn, InsnPatch, Part, Program},
+};
+use anyhow::{self as ah, format_err as err};
+use std::collections::HashSet;
+
+/// I/O addresses of the stack pointer.
+const SPL_IO: u8 = 0x3d;
+const SPH_IO: u8 = 0x3e;
+
+fn push_pop_reg(insn: &Insn, name: &str) -> Option<u8> {
+    if insn.name() == name && insn.ops().len() == 1 {
+        insn.ops()[0].reg()
+    } else {
+        None
+    }
+}
+
+/// The stack layout is observable through the stack pointer or a Y frame pointer.
+fn stack_observable(insn: &Insn) -> bool {
+    (insn.name() == "in" && matches!(insn.ops()[1].io(), Some(SPL_IO | SPH_IO)))
+        || insn
+            .ops()
+            .iter()
+            .any(|op| matches!(op.ptr(), Some((PtrReg::Y, _))))
+}
+
+/// Instruction indices of the callee-saved pushes in the prologue
+/// and of the matching pops before each exit, by register.
+struct Frame {
+    regs: Vec<(u8, Vec<usize>)>,
+}
+
+/// Find the callee-save prologue and the epilogues of a function.
+/// Returns `None`, if the function doesn't follow the expected pattern.
+fn find_frame(part: &Part, cfg: &Cfg) -> Option<Frame> {
+    let insns = part.insns();
+
+    // Prologue: The leading pushes of the entry block.
+    let entry = cfg.entry()?.range();
+    let mut pushes = vec![];
+    for i in entry {
+        match push_pop_reg(&insns[i], "push") {
+            Some(reg) if reg_is_callee_saved(reg) => pushes.push((reg, i)),
+            _ => break,
+        }
+    }
+    if pushes.is_empty() {
+        return None;
+    }
+
+    // Exits: ret or a tail jump to another function.
+    let reachable = cfg.reachable();
+    let mut exits = vec![];
+    for (b, block) in cfg.blocks().iter().enumerate() {
+        if !reachable[b] {
+            continue;
+        }
+        let last = block.range().end - 1;
+        for edge in block.succs() {
+            match &edge.target {
+                EdgeTarget::Block(_) => (),
+                EdgeTarget::Label(_) if insns[last].flow() == Flow::Jump => exits.push(last),
+                _ => return None,
+            }
+        }
+        match insns[last].name() {
+            "ret" => exits.push(last),
+            "reti" => return None,
+            _ => (),
+        }
+    }
+    if exits.is_empty() {
+        return None;
+    }
+
+    // Epilogue: The pops in reverse push order right before each exit.
+    let mut regs: Vec<(u8, Vec<usize>)> = pushes.iter().map(|&(r, i)| (r, vec![i])).collect();
+    for exit in exits {
+        let start = cfg.block(cfg.block_of(exit)).start();
+        if exit - start < pushes.len() {
+            return None;
+        }
+        for (n, (reg, indices)) in regs.iter_mut().enumerate() {
+            let i = exit - 1 - n;
+            if push_pop_reg(&insns[i], "pop") != Some(*reg) {
+                return None;
+            }
+            indices.push(i);
+        }
+    }
+    Some(Frame { regs })
+}
+
+/// Remove the push/pop pairs of registers that the function never writes.
+/// Returns the number of removed pairs.
+fn remove_pairs(part: &mut Part, inner_refs: &HashSet<String>) -> usize {
+    // The function must only be entered at its start.
+    if part
+        .insns()
+        .iter()
+        .filter_map(|i| i.label())
+        .any(|l| inner_refs.contains(l))
+    {
+        return 0;
+    }
+    let cfg = Cfg::new(part);
+    let Some(frame) = find_frame(part, &cfg) else {
+        return 0;
+    };
+    let frame_insns: HashSet<usize> = frame.regs.iter().flat_map(|(_, i)| i).copied().collect();
+
+    let mut defs = RegSet::empty();
+    for (i, insn) in part.insns().iter().enumerate() {
+        if stack_observable(insn) {
+            return 0;
+        }
+        if !frame_insns.contains(&i) {
+            defs = defs.union(insn.reg_defs());
+        }
+    }
+
+    let mut removed = 0;
+    for (reg, indices) in &frame.regs {
+        if defs.contains(*reg) {
+            continue;
+        }
+        // Removing an instruction from a skip shadow would change the skip target.
+        if indices.iter().any(|&i| part.in_skip_shadow(i)) {
+            continue;
+        }
+        for &i in indices {
+            part.insn_at_mut(i).set_patch(Some(InsnPatch::empty()));
+        }
+        removed += 1;
+    }
+    removed
+}
+
+pub async fn run(program: &mut Program) -> ah::Result<()> {
+    let Some(text) = program.section_text_mut() else {
+        return Err(err!("Text section not found."));
+    };
+    text.apply_patches()?;
+
+    // Labels inside of parts that are referenced from other parts.
+    let mut inner_refs = HashSet::new();
+    for part in text.parts() {
+        let own: HashSet<&str> = part.insns().iter().filter_map(|i| i.label()).collect();
+        for insn in part.insns() {
+            for op in insn.ops() {
+                let label = match op {
+                    Operand::Label(label) => label,
+                    Operand::Expr(expr) => &expr.label,
+                    _ => continue,
+                };
+                if !own.contains(label.as_str()) {
+                    inner_refs.insert(label.clone());
+                }
+            }
+        }
+    }
+
+    let mut total = 0;
+    println!("Callee-saved push/pop elimination:");
+    for part in text.parts_mut() {
+        let removed = remove_pairs(part, &inner_refs);
+        if removed > 0 {
+            println!("  {}: {removed} push/pop pairs removed", part.name());
+        }
+        total += removed;
+    }
+    println!("Callee-saved registers: {total} push/pop pairs removed.");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{block_on, part_asm, program};
+
+    fn callee_saved(body: &str) -> Vec<String> {
+        let mut program = program("atmega328p", &format!("f:\n{body}"));
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        part_asm(&program, "f")
+    }
+
+    #[test]
+    fn test_remove_unused() {
+        assert_eq!(
+            callee_saved(
+                "
+    push r16
+    push r17
+    mov r17, r24
+    breq l
+    pop r17
+    pop r16
+    ret
+    l: pop r17
+    pop r16
+    ret
+"
+            ),
+            ["push r17", "mov r17, r24", "breq l", "pop r17", "ret", "l: pop r17", "ret"]
+        );
+    }
+
+    #[test]
+    fn test_keep() {
+        // r16 is written.
+        let body = "    push r16\n    ldi r16, 0x01\n    pop r16\n    ret\n";
+        assert_eq!(callee_saved(body).len(), 4);
+        // The frame is accessed through Y.
+        let body = "    push r28\n    push r29\n    ldd r24, Y+1\n    pop r29\n    pop r28\n    ret\n";
+        assert_eq!(callee_saved(body).len(), 6);
+        // The pops don't match the pushes.
+        let body = "    push r16\n    push r17\n    pop r16\n    pop r17\n    ret\n";
+        assert_eq!(callee_saved(body).len(), 5);
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -0,0 +1,126 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+use crate::{callgraph::CallGraph, program::Program};
+use anyhow::{self as ah, format_err as err};
+
+/// Parts that are entered through addresses computed at run time.
+const KEEP_PARTS: &[&str] = &["__trampolines_start"];
+
+pub async fn run(program: &mut Program) -> ah::Result<()> {
+    let graph = CallGraph::new(program);
+    if graph.roots().is_empty() {
+        return Err(err!("Interrupt vector table not found."));
+    }
+
+    // Everything that can be reached from the vector table.
+    // Parts with an observable address might be called indirectly.
+    let reachable = graph.reachable_from(
+        graph
+            .roots()
+            .iter()
+            .copied()
+            .chain(graph.address_taken()),
+    );
+
+    let Some(text) = program.section_text_mut() else {
+        return Err(err!("Text section not found."));
+    };
+
+    let mut removed = vec![];
+    for (i, part) in text.parts_mut().iter_mut().enumerate() {
+        if reachable[i]
+            || KEEP_PARTS.contains(&part.name())
+            || part.final_part().insns().is_empty()
+        {
+            continue;
+        }
+        let size: u32 = part.final_insns().map(|insn| insn.size_words() * 2).sum();
+        removed.push((part.name().to_string(), part.demangled().to_string(), size));
+        part.set_patch_delete_part();
+    }
+
+    println!("Dead functions removed:");
+    for (name, demangled, size) in &removed {
+        if name == demangled {
+            println!("  {name} ({size} bytes)");
+        } else {
+            println!("  {name} ({size} bytes) ; {demangled}");
+        }
+    }
+    let saved: u32 = removed.iter().map(|(_, _, size)| size).sum();
+    println!("Dead functions: {} removed, {saved} bytes saved.", removed.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        layout::{Layout, layout_program},
+        operand::Operand,
+        testutil::{block_on, part_asm, program},
+    };
+
+    #[test]
+    fn test_remove_unused() {
+        let mut program = program(
+            "atmega328p",
+            "
+__vectors:
+    jmp main
+unused:
+    ret
+used:
+    ret
+main:
+    rcall used
+    rjmp main
+",
+        );
+        block_on(run(&mut program)).unwrap();
+        layout_program(&mut program).unwrap();
+        assert!(part_asm(&program, "unused").is_empty());
+        assert_eq!(part_asm(&program, "used"), ["ret"]);
+        assert_eq!(part_asm(&program, "main"), ["rcall used", "rjmp main"]);
+    }
+
+    #[test]
+    fn test_keep_address_taken() {
+        // Functions called through a pointer are kept and the pointer follows the move.
+        let mut program = program(
+            "atmega328p",
+            "
+__vectors:
+    jmp main
+unused:
+    ret
+target:
+    ret
+main:
+    ldi r30, 0x03
+    ldi r31, 0x00
+    icall
+    rjmp main
+",
+        );
+        block_on(run(&mut program)).unwrap();
+        layout_program(&mut program).unwrap();
+        assert!(part_asm(&program, "unused").is_empty());
+        assert_eq!(part_asm(&program, "target"), ["ret"]);
+
+        let layout = Layout::new(&program).unwrap();
+        assert_eq!(layout.label_addr("target"), Some(2));
+        let text = program.section_text().unwrap();
+        let main = text.final_parts().find(|p| p.name() == "main").unwrap();
+        let lo = main.final_insns().next().unwrap();
+        let Operand::Expr(expr) = lo.ops().last().unwrap() else {
+            panic!("Function pointer not relocated: {lo}");
+        };
+        assert_eq!(expr.eval(layout.resolve(&expr.label).unwrap()), 2);
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
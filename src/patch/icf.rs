@@ -0,0 +1,268 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+use crate::{
+    callgraph::{CallGraph, RefKind},
+    cfg::Cfg,
+    layout::{Layout, rel_in_range, rel_range},
+    operand::Operand,
+    program::{Part, Program},
+};
+use anyhow::{self as ah, format_err as err};
+use std::collections::{HashMap, HashSet};
+
+/// Parts that are entered through addresses computed at run time.
+const KEEP_PARTS: &[&str] = &["__trampolines_start"];
+
+/// Instructions of a part with all part-internal labels
+/// replaced by the index of the labeled instruction.
+fn normalized(part: &Part) -> Vec<(String, Vec<Operand>)> {
+    let mut internal = HashMap::new();
+    internal.insert(part.name(), 0);
+    for (i, insn) in part.insns().iter().enumerate() {
+        if let Some(label) = insn.label() {
+            internal.insert(label, i);
+        }
+    }
+    let norm = |label: &str| match internal.get(label) {
+        Some(i) => format!("@{i}"),
+        None => label.to_string(),
+    };
+    part.insns()
+        .iter()
+        .map(|insn| {
+            let ops = insn
+                .ops()
+                .iter()
+                .map(|op| match op {
+                    Operand::Label(label) => Operand::Label(norm(label)),
+                    Operand::Expr(expr) => {
+                        let mut expr = expr.clone();
+                        expr.label = norm(&expr.label);
+                        Operand::Expr(expr)
+                    }
+                    op => op.clone(),
+                })
+                .collect();
+            (insn.name().to_string(), ops)
+        })
+        .collect()
+}
+
+pub async fn run(program: &mut Program) -> ah::Result<()> {
+    let Some(device) = program.device() else {
+        return Err(err!("No device info."));
+    };
+    let flash_size = device.flash_size;
+    let Some(text) = program.section_text_mut() else {
+        return Err(err!("Text section not found."));
+    };
+    text.apply_patches()?;
+    let layout = Layout::new(program)?;
+    let graph = CallGraph::new(program);
+    let text = program.section_text_mut().unwrap();
+
+    // Addresses of the relative jumps, branches and calls to each label.
+    let mut rel_refs: HashMap<&str, Vec<(u32, &str)>> = HashMap::new();
+    let mut addr = 0;
+    for part in text.parts() {
+        for insn in part.insns() {
+            if rel_range(insn.name()).is_some()
+                && let Some(label) = insn.ops().last().and_then(|op| op.label())
+            {
+                rel_refs.entry(label).or_default().push((addr, insn.name()));
+            }
+            addr += insn.size_words();
+        }
+    }
+
+    // Labels inside of parts that are referenced from other parts.
+    let mut inner_refs = HashSet::new();
+    for part in text.parts() {
+        let own: HashSet<&str> = part.insns().iter().filter_map(|i| i.label()).collect();
+        for insn in part.insns() {
+            for op in insn.ops() {
+                let label = match op {
+                    Operand::Label(label) => label,
+                    Operand::Expr(expr) => &expr.label,
+                    _ => continue,
+                };
+                if !own.contains(label.as_str()) {
+                    inner_refs.insert(label.clone());
+                }
+            }
+        }
+    }
+
+    let mut unique: HashMap<Vec<(String, Vec<Operand>)>, usize> = HashMap::new();
+    let mut folds = vec![];
+    for (p, part) in text.parts().iter().enumerate() {
+        if part.insns().is_empty()
+            || graph.roots().contains(&p)
+            || KEEP_PARTS.contains(&part.name())
+            || Cfg::new(part).falls_through_end()
+        {
+            continue;
+        }
+        let key = normalized(part);
+        let Some(&keep) = unique.get(&key) else {
+            unique.insert(key, p);
+            continue;
+        };
+        // The duplicate must only be entered through its name.
+        // Parts with observable addresses (data references, indirect calls)
+        // and parts entered by falling through the previous part stay.
+        let removable = graph
+            .edges_to(p)
+            .all(|e| matches!(e.kind, RefKind::Call | RefKind::Jump))
+            && !part
+                .insns()
+                .iter()
+                .filter_map(|i| i.label())
+                .any(|l| inner_refs.contains(l));
+        // Don't make relative jumps, branches or calls go out of range.
+        // The layout would have to grow them.
+        let keep_addr = layout.resolve(text.part_at(keep).name())?;
+        let in_range = rel_refs.get(part.name()).is_none_or(|refs| {
+            refs.iter()
+                .all(|&(addr, name)| rel_in_range(name, addr, keep_addr, flash_size))
+        });
+        if removable && in_range {
+            folds.push((p, keep));
+        }
+    }
+
+    let mut saved = 0;
+    println!("Identical code folding:");
+    for &(p, keep) in &folds {
+        let size: u32 = text.part_at(p).insns().iter().map(|i| i.size_words() * 2).sum();
+        saved += size;
+        let name = text.part_at(p).name().to_string();
+        let keep_name = text.part_at(keep).name().to_string();
+        println!("  {name} -> {keep_name} ({size} bytes)");
+        text.rename_label(&name, &keep_name);
+        text.part_at_mut(p).set_patch_delete_part();
+    }
+    println!("ICF: {} parts folded, {saved} bytes saved.", folds.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{block_on, part_asm, program};
+
+    fn icf(asm: &str) -> Program {
+        let mut program = program("atmega328p", asm);
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        program
+    }
+
+    #[test]
+    fn test_fold() {
+        let program = icf("
+__vectors:
+    jmp main
+main:
+    rcall a
+    rcall b
+    rjmp main
+a:
+    ldi r24, 0x01
+    l1: dec r24
+    brne l1
+    ret
+b:
+    ldi r24, 0x01
+    l2: dec r24
+    brne l2
+    ret
+");
+        assert_eq!(part_asm(&program, "main"), ["rcall a", "rcall a", "rjmp main"]);
+        assert!(part_asm(&program, "b").is_empty());
+    }
+
+    #[test]
+    fn test_keep() {
+        let program = icf("
+__vectors:
+    jmp main
+main:
+    rcall a
+    rcall b
+    ldi r30, 0x0C
+    ldi r31, 0x00
+    icall
+    rjmp main
+a:
+    ldi r24, 0x01
+    ret
+b:
+    ldi r24, 0x01
+    ret
+c:
+    ldi r24, 0x01
+    ret
+");
+        // The address of c is taken.
+        assert_eq!(part_asm(&program, "c"), ["ldi r24, 0x01", "ret"]);
+        assert!(part_asm(&program, "b").is_empty());
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let asm = "
+__vectors:
+    jmp main
+a:
+    ldi r24, 0x01
+    ret
+far:
+"
+        .to_string()
+            + &"    nop\n".repeat(2100)
+            + "    ret
+main:
+    call a
+    call far
+    rcall b
+    rjmp main
+b:
+    ldi r24, 0x01
+    ret
+";
+        // The rcall can't reach a.
+        let program = icf(&asm);
+        assert_eq!(part_asm(&program, "b"), ["ldi r24, 0x01", "ret"]);
+        assert_eq!(part_asm(&program, "main")[2], "rcall b");
+
+        // The call can.
+        let program = icf(&asm.replace("rcall b", "call b"));
+        assert!(part_asm(&program, "b").is_empty());
+        assert_eq!(part_asm(&program, "main")[2], "call a");
+    }
+
+    #[test]
+    fn test_different() {
+        let program = icf("
+__vectors:
+    jmp main
+main:
+    rcall a
+    rcall b
+    rjmp main
+a:
+    ldi r24, 0x01
+    ret
+b:
+    ldi r24, 0x02
+    ret
+");
+        assert_eq!(part_asm(&program, "main"), ["rcall a", "rcall b", "rjmp main"]);
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -0,0 +1,204 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+use crate::{
+    cfg::Cfg,
+    insninfo::CoreFamily,
+    liveness::Liveness,
+    operand::Operand,
+    program::{Insn, InsnPatch, Part, Program},
+};
+use anyhow::{self as ah, format_err as err};
+
+/// Number of I/O registers reachable with in/out.
+const IO_SIZE: u32 = 0x40;
+/// Number of I/O registers reachable with sbi/cbi.
+const LOW_IO_SIZE: u8 = 0x20;
+
+/// Data space address of I/O address 0.
+fn io_offset(family: CoreFamily) -> u32 {
+    match family {
+        CoreFamily::Avre | CoreFamily::Avre22 => 0x20,
+        CoreFamily::Xmega | CoreFamily::Xt | CoreFamily::Rc => 0,
+    }
+}
+
+/// Convert lds/sts of the I/O space to in/out.
+/// Returns the number of converted instructions.
+fn convert_lds_sts(part: &mut Part, offset: u32) -> usize {
+    let to_io = |op: &Operand| {
+        op.data()
+            .and_then(|a| a.checked_sub(offset))
+            .filter(|&a| a < IO_SIZE)
+            .map(|a| Operand::Io(a as u8))
+    };
+    let mut count = 0;
+    for i in 0..part.insns().len() {
+        if part.in_skip_shadow(i) {
+            continue;
+        }
+        let insn = part.insn_at(i);
+        let new = match insn.name() {
+            "lds" => to_io(&insn.ops()[1]).map(|io| ("in", vec![insn.ops()[0].clone(), io])),
+            "sts" => to_io(&insn.ops()[0]).map(|io| ("out", vec![io, insn.ops()[1].clone()])),
+            _ => None,
+        };
+        if let Some((name, ops)) = new {
+            let new = Insn::new(name, ops, None, insn.addr());
+            part.insn_at_mut(i).set_patch(Some(InsnPatch::new(vec![new])));
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Convert single bit read-modify-write sequences on the low I/O space to sbi/cbi:
+/// `in rX, A; ori rX, 1<<b; out A, rX` and `in rX, A; andi rX, ~(1<<b); out A, rX`.
+/// Returns the number of converted sequences.
+fn convert_rmw(part: &mut Part) -> usize {
+    let cfg = Cfg::new(part);
+    let liveness = Liveness::new(part.insns(), &cfg);
+    let insns = part.insns();
+
+    let mut seqs = vec![];
+    for i in 0..insns.len().saturating_sub(2) {
+        let (rd, op, wr) = (&insns[i], &insns[i + 1], &insns[i + 2]);
+        if rd.name() != "in"
+            || wr.name() != "out"
+            || part.in_skip_shadow(i)
+            || op.label().is_some()
+            || wr.label().is_some()
+            || cfg.block_of(i) != cfg.block_of(i + 2)
+        {
+            continue;
+        }
+        let (Some(reg), Some(io)) = (rd.ops()[0].reg(), rd.ops()[1].io()) else {
+            continue;
+        };
+        if io >= LOW_IO_SIZE
+            || op.ops().first().and_then(|o| o.reg()) != Some(reg)
+            || wr.ops()[0].io() != Some(io)
+            || wr.ops()[1].reg() != Some(reg)
+        {
+            continue;
+        }
+        let Some(imm) = op.ops().get(1).and_then(|o| o.imm()).map(|v| v as u8) else {
+            continue;
+        };
+        let (name, mask) = match op.name() {
+            "ori" => ("sbi", imm),
+            "andi" => ("cbi", !imm),
+            _ => continue,
+        };
+        if mask.count_ones() != 1 {
+            continue;
+        }
+        // The scratch register and the flags of ori/andi must not be used afterwards.
+        let live = liveness.live_out(i + 2);
+        if live.regs.contains(reg) || live.sreg & op.sreg_defs() != 0 {
+            continue;
+        }
+        let bit = mask.trailing_zeros() as u8;
+        seqs.push((i, Insn::new(name, vec![Operand::Io(io), Operand::Bit(bit)], None, rd.addr())));
+    }
+
+    let mut last_end = 0;
+    let mut count = 0;
+    for (i, new) in seqs {
+        // Overlapping sequences.
+        if i < last_end {
+            continue;
+        }
+        part.insn_at_mut(i).set_patch(Some(InsnPatch::new(vec![new])));
+        part.insn_at_mut(i + 1).set_patch(Some(InsnPatch::empty()));
+        part.insn_at_mut(i + 2).set_patch(Some(InsnPatch::empty()));
+        last_end = i + 3;
+        count += 1;
+    }
+    count
+}
+
+pub async fn run(program: &mut Program) -> ah::Result<()> {
+    let offset = io_offset(program.core_family());
+    let Some(text) = program.section_text_mut() else {
+        return Err(err!("Text section not found."));
+    };
+    text.apply_patches()?;
+
+    let mut converted = 0;
+    for part in text.parts_mut() {
+        converted += convert_lds_sts(part, offset);
+    }
+    text.apply_patches()?;
+
+    let mut rmw = 0;
+    for part in text.parts_mut() {
+        rmw += convert_rmw(part);
+    }
+
+    println!("I/O access: {converted} lds/sts converted to in/out, {rmw} sbi/cbi created.");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{block_on, part_asm, program};
+
+    fn io_access(body: &str) -> Vec<String> {
+        let mut program = program("atmega328p", &format!("f:\n{body}"));
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        part_asm(&program, "f")
+    }
+
+    #[test]
+    fn test_lds_sts() {
+        assert_eq!(
+            io_access("    lds r24, 0x0025\n    sts 0x005F, r24\n    sts 0x0060, r24\n    ret\n"),
+            ["in r24, 0x05", "out 0x3f, r24", "sts 0x0060, r24", "ret"]
+        );
+        // The skip shadow must keep its size.
+        assert_eq!(
+            io_access("    sbrc r24, 0\n    lds r24, 0x0025\n    ret\n"),
+            ["sbrc r24, 0", "lds r24, 0x0025", "ret"]
+        );
+    }
+
+    #[test]
+    fn test_sbi_cbi() {
+        assert_eq!(
+            io_access(
+                "
+    in r24, 0x05
+    ori r24, 0x20
+    out 0x05, r24
+    in r24, 0x05
+    andi r24, 0xFE
+    out 0x05, r24
+    ldi r24, 0x00
+    cp r24, r25
+    ret
+"
+            ),
+            ["sbi 0x05, 5", "cbi 0x05, 0", "ldi r24, 0x00", "cp r24, r25", "ret"]
+        );
+    }
+
+    #[test]
+    fn test_keep_rmw() {
+        // The register is used afterwards.
+        let body = "    in r24, 0x05\n    ori r24, 0x20\n    out 0x05, r24\n    ret\n";
+        assert_eq!(io_access(body).len(), 4);
+        // More than one bit.
+        let body = "    in r24, 0x05\n    ori r24, 0x21\n    out 0x05, r24\n    ldi r24, 0x00\n    cp r24, r25\n    ret\n";
+        assert_eq!(io_access(body).len(), 6);
+        // Not in the low I/O space.
+        let body = "    in r24, 0x25\n    ori r24, 0x20\n    out 0x25, r24\n    ldi r24, 0x00\n    cp r24, r25\n    ret\n";
+        assert_eq!(io_access(body).len(), 6);
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
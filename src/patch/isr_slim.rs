@@ -0,0 +1,322 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+use crate::{
+    callgraph::VECTORS,
+    cfg::{Cfg, EdgeTarget},
+    insninfo::sreg,
+    liveness::{LiveSet, Liveness},
+    program::{Insn, InsnPatch, Part, Program},
+};
+use anyhow::{self as ah, format_err as err};
+use std::collections::HashSet;
+
+/// I/O addresses of the stack pointer.
+const SPL_IO: u8 = 0x3d;
+const SPH_IO: u8 = 0x3e;
+
+/// Vector targets that are not interrupt handlers.
+const NOT_HANDLERS: &[&str] = &["__ctors_end", "__bad_interrupt", "_exit", VECTORS];
+
+fn is_insn(insn: &Insn, name: &str, reg: u8) -> bool {
+    insn.name() == name && insn.ops().first().and_then(|op| op.reg()) == Some(reg)
+}
+
+fn is_sreg_in(insn: &Insn) -> bool {
+    is_insn(insn, "in", 0) && insn.ops()[1].io() == Some(sreg::IO)
+}
+
+fn is_sreg_out(insn: &Insn) -> bool {
+    insn.name() == "out" && insn.ops()[0].io() == Some(sreg::IO) && insn.ops()[1].reg() == Some(0)
+}
+
+/// Indices of the save and restore instructions of an interrupt handler.
+#[derive(Default)]
+struct Frame {
+    /// push r0, pop r0 (for each reti)
+    r0: Vec<usize>,
+    /// push r1, pop r1 (for each reti)
+    r1: Vec<usize>,
+    /// in r0, 0x3f; push r0; pop r0; out 0x3f, r0 (for each reti)
+    sreg: Vec<usize>,
+    /// eor r1, r1
+    clr_r1: Vec<usize>,
+}
+
+impl Frame {
+    fn all(&self) -> HashSet<usize> {
+        [&self.r0, &self.r1, &self.sreg, &self.clr_r1]
+            .into_iter()
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
+
+/// Find the standard prologue and epilogues of an interrupt handler.
+/// Returns `None`, if the handler doesn't follow the expected pattern.
+fn find_frame(part: &Part, cfg: &Cfg) -> Option<Frame> {
+    let insns = part.insns();
+    let mut frame = Frame::default();
+
+    // Prologue in the entry block.
+    let entry = cfg.entry()?.range();
+    let mut i = entry.start;
+    while i < entry.end {
+        let insn = &insns[i];
+        if is_sreg_in(insn) && i + 1 < entry.end && is_insn(&insns[i + 1], "push", 0) {
+            frame.sreg.extend([i, i + 1]);
+            i += 2;
+            continue;
+        }
+        if is_insn(insn, "push", 0) {
+            frame.r0.push(i);
+        } else if is_insn(insn, "push", 1) {
+            frame.r1.push(i);
+        } else if is_insn(insn, "eor", 1) && insn.ops()[1].reg() == Some(1) {
+            frame.clr_r1.push(i);
+        } else if insn.name() != "push" {
+            break;
+        }
+        i += 1;
+    }
+    if frame.r0.len() != 1 || frame.r1.len() != 1 || frame.sreg.len() != 2 {
+        return None;
+    }
+
+    // Epilogue before each reti.
+    let retis: Vec<usize> = (0..insns.len())
+        .filter(|&i| insns[i].name() == "reti")
+        .collect();
+    if retis.is_empty() || insns.iter().any(|i| i.name() == "ret") {
+        return None;
+    }
+    for reti in retis {
+        let block = cfg.block(cfg.block_of(reti));
+        let (mut r0, mut r1, mut sreg) = (None, None, None);
+        let mut i = reti;
+        while i > block.start() {
+            i -= 1;
+            let insn = &insns[i];
+            if is_sreg_out(insn) && i > block.start() && is_insn(&insns[i - 1], "pop", 0) {
+                sreg = Some([i - 1, i]);
+                i -= 1;
+            } else if is_insn(insn, "pop", 0) {
+                r0 = Some(i);
+            } else if is_insn(insn, "pop", 1) {
+                r1 = Some(i);
+            } else if insn.name() != "pop" {
+                break;
+            }
+        }
+        frame.r0.push(r0?);
+        frame.r1.push(r1?);
+        frame.sreg.extend(sreg?);
+    }
+    Some(frame)
+}
+
+/// Remove the unneeded parts of the interrupt handler frame.
+/// Returns the number of removed instructions.
+fn slim_handler(part: &mut Part) -> usize {
+    let cfg = Cfg::new(part);
+    let Some(frame) = find_frame(part, &cfg) else {
+        return 0;
+    };
+    let frame_insns = frame.all();
+
+    // The frame only works, if the handler doesn't leave the part.
+    let leaves = cfg
+        .blocks()
+        .iter()
+        .flat_map(|b| b.succs())
+        .any(|e| !matches!(e.target, EdgeTarget::Block(_)));
+    if leaves {
+        return 0;
+    }
+
+    // Everything the handler body writes.
+    let mut defs = LiveSet::empty();
+    for (i, insn) in part.insns().iter().enumerate() {
+        if frame_insns.contains(&i) || insn.name() == "reti" {
+            continue;
+        }
+        // Stack pointer relative accesses would see the changed frame.
+        if insn.name() == "in" && matches!(insn.ops()[1].io(), Some(SPL_IO | SPH_IO)) {
+            return 0;
+        }
+        defs = defs.union(LiveSet::defs(insn));
+    }
+
+    // Does the body need r1 to be zero on entry?
+    // The frame is transparent and reti doesn't need anything,
+    // because the interrupted context is restored by the epilogue.
+    let liveness = Liveness::with_effects(part.insns(), &cfg, LiveSet::all(), |i, insn| {
+        if frame_insns.contains(&i) || insn.name() == "reti" {
+            (LiveSet::empty(), LiveSet::empty())
+        } else {
+            (LiveSet::defs(insn), LiveSet::uses(insn))
+        }
+    });
+    let need_zero_r1 = liveness.live_in(0).regs.contains(1);
+
+    let need_clr_r1 = need_zero_r1;
+    let need_r1 = defs.regs.contains(1) || need_clr_r1;
+    // The kept eor r1, r1 writes the flags, too.
+    let mut sreg_defs = defs.sreg;
+    if need_clr_r1 {
+        for &i in &frame.clr_r1 {
+            sreg_defs |= part.insn_at(i).sreg_defs();
+        }
+    }
+    let need_sreg = sreg_defs != 0;
+    let need_r0 = defs.regs.contains(0) || need_sreg;
+
+    let mut remove = vec![];
+    if !need_sreg {
+        remove.extend(&frame.sreg);
+    }
+    if !need_r0 {
+        remove.extend(&frame.r0);
+    }
+    if !need_clr_r1 {
+        remove.extend(&frame.clr_r1);
+    }
+    if !need_r1 {
+        remove.extend(&frame.r1);
+    }
+    for &i in &remove {
+        part.insn_at_mut(i).set_patch(Some(InsnPatch::empty()));
+    }
+    remove.len()
+}
+
+pub async fn run(program: &mut Program) -> ah::Result<()> {
+    let Some(text) = program.section_text_mut() else {
+        return Err(err!("Text section not found."));
+    };
+    text.apply_patches()?;
+
+    // Find the interrupt handlers through the vector table.
+    let Some(vectors) = text.find_part(VECTORS) else {
+        return Err(err!("Interrupt vector table not found."));
+    };
+    let mut handlers = vec![];
+    for insn in vectors.insns().iter().skip(1) {
+        if ["rjmp", "jmp"].contains(&insn.name())
+            && let Some(target) = insn.ops()[0].label()
+            && !NOT_HANDLERS.contains(&target)
+            && !handlers.iter().any(|h| h == target)
+        {
+            handlers.push(target.to_string());
+        }
+    }
+
+    for handler in &handlers {
+        let Some(part) = text.find_part_mut(handler) else {
+            // The vector doesn't point to the start of a part.
+            continue;
+        };
+        let removed = slim_handler(part);
+        println!("ISR slimming: {handler}: {removed} instructions removed.");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{block_on, part_asm, program};
+
+    fn handler(body: &str) -> Program {
+        let mut program = program(
+            "atmega328p",
+            &format!(
+                "
+__vectors:
+    jmp __ctors_end
+    jmp __vector_1
+__vector_1:
+    push r1
+    push r0
+    in r0, 0x3f
+    push r0
+    eor r1, r1
+{body}
+    pop r0
+    out 0x3f, r0
+    pop r0
+    pop r1
+    reti
+other:
+    reti
+"
+            ),
+        );
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        program
+    }
+
+    #[test]
+    fn test_slim() {
+        let program = handler(
+            "
+    push r24
+    lds r24, 0x0100
+    sts 0x0101, r24
+    pop r24
+",
+        );
+        assert_eq!(
+            part_asm(&program, "__vector_1"),
+            [
+                "push r24",
+                "lds r24, 0x0100",
+                "sts 0x0101, r24",
+                "pop r24",
+                "reti"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keep_sreg() {
+        let program = handler(
+            "
+    push r24
+    lds r24, 0x0100
+    inc r24
+    sts 0x0100, r24
+    pop r24
+",
+        );
+        let asm = part_asm(&program, "__vector_1");
+        assert_eq!(asm.len(), 12);
+        assert_eq!(asm[..3], ["push r0", "in r0, 0x3f", "push r0"]);
+        assert!(!asm.contains(&"eor r1, r1".to_string()));
+    }
+
+    #[test]
+    fn test_zero_r1_clobbers_sreg() {
+        // The kept eor r1, r1 writes the flags.
+        let program = handler("    sts 0x0104, r1\n");
+        assert_eq!(part_asm(&program, "__vector_1").len(), 11);
+    }
+
+    #[test]
+    fn test_leaves_part() {
+        let program = handler(
+            "
+    lds r0, 0x0100
+    sbrc r0, 0
+    rjmp other
+",
+        );
+        assert_eq!(part_asm(&program, "__vector_1").len(), 13);
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -0,0 +1,227 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+use crate::{
+    callgraph::CallGraph,
+    cfg::Cfg,
+    insninfo::Flow,
+    layout::{Layout, rel_in_range, rel_range},
+    operand::Operand,
+    program::{CodeSection, Insn, InsnPatch, Program},
+};
+use anyhow::{self as ah, format_err as err};
+use std::collections::{HashMap, HashSet};
+
+/// Parts that are entered through addresses computed at run time.
+const KEEP_PARTS: &[&str] = &["__trampolines_start"];
+
+/// Get the flash label operand of a jump, branch or call.
+fn target_label(insn: &Insn) -> Option<&str> {
+    match insn.flow() {
+        Flow::Jump | Flow::Branch | Flow::Call => insn.ops().last().and_then(|op| op.label()),
+        _ => None,
+    }
+}
+
+/// The instruction is a stub that unconditionally jumps to a label.
+fn is_stub_jump(insn: &Insn) -> bool {
+    insn.flow() == Flow::Jump && target_label(insn).is_some()
+}
+
+/// Follow a chain of unconditional jumps starting at `label`.
+/// Returns the final destination, or `None` if it is `label` itself
+/// or if the chain is a loop.
+fn final_target(
+    text: &CodeSection,
+    labels: &HashMap<String, (usize, usize)>,
+    label: &str,
+) -> Option<String> {
+    let mut visited = HashSet::new();
+    let mut cur = label.to_string();
+    while let Some(&(p, i)) = labels.get(&cur) {
+        let insn = text.part_at(p).insn_at(i);
+        if !is_stub_jump(insn) {
+            break;
+        }
+        visited.insert(cur.clone());
+        let next = target_label(insn).unwrap();
+        if visited.contains(next) {
+            return None;
+        }
+        cur = next.to_string();
+    }
+    (cur != label).then_some(cur)
+}
+
+/// Map all labels to the (part, instruction) index of the labeled instruction.
+fn label_map(text: &CodeSection) -> HashMap<String, (usize, usize)> {
+    let mut labels = HashMap::new();
+    for (p, part) in text.parts().iter().enumerate() {
+        if !part.insns().is_empty() {
+            labels.insert(part.name().to_string(), (p, 0));
+        }
+        for (i, insn) in part.insns().iter().enumerate() {
+            if let Some(label) = insn.label() {
+                labels.insert(label.to_string(), (p, i));
+            }
+        }
+    }
+    labels
+}
+
+/// All labels that are referenced by any instruction operand.
+fn referenced_labels(text: &CodeSection) -> HashSet<String> {
+    let mut refs = HashSet::new();
+    for part in text.parts() {
+        for insn in part.insns() {
+            for op in insn.ops() {
+                match op {
+                    Operand::Label(label) => refs.insert(label.clone()),
+                    Operand::Expr(expr) => refs.insert(expr.label.clone()),
+                    _ => false,
+                };
+            }
+        }
+    }
+    refs
+}
+
+pub async fn run(program: &mut Program) -> ah::Result<()> {
+    let Some(device) = program.device() else {
+        return Err(err!("No device info."));
+    };
+    let flash_size = device.flash_size;
+    let Some(text) = program.section_text_mut() else {
+        return Err(err!("Text section not found."));
+    };
+    text.apply_patches()?;
+    let layout = Layout::new(program)?;
+    let text = program.section_text_mut().unwrap();
+
+    // Retarget all jumps, branches and calls to the end of jump chains.
+    let labels = label_map(text);
+    let mut retargets = vec![];
+    let mut addr = 0;
+    for (p, part) in text.parts().iter().enumerate() {
+        for (i, insn) in part.insns().iter().enumerate() {
+            if let Some(label) = target_label(insn)
+                && let Some(dest) = final_target(text, &labels, label)
+            {
+                // Don't make relative jumps, branches or calls go out of range.
+                // The layout would have to grow them.
+                let in_range = rel_range(insn.name()).is_none()
+                    || rel_in_range(insn.name(), addr, layout.resolve(&dest)?, flash_size);
+                if in_range {
+                    retargets.push((p, i, dest));
+                }
+            }
+            addr += insn.size_words();
+        }
+    }
+    let retargeted = retargets.len();
+    for (p, i, dest) in retargets {
+        let insn = text.part_at_mut(p).insn_at_mut(i);
+        let mut new_insn = insn.clone();
+        let last = new_insn.ops().len() - 1;
+        new_insn.set_op(last, Operand::Label(dest));
+        insn.set_patch(Some(InsnPatch::new(vec![new_insn])));
+    }
+    text.apply_patches()?;
+
+    // Remove the stubs that are not referenced anymore.
+    let graph = CallGraph::new(program);
+    let text = program.section_text_mut().unwrap();
+    let refs = referenced_labels(text);
+    let mut removed = 0;
+    for (p, part) in text.parts_mut().iter_mut().enumerate() {
+        // The vector table and the trampolines are entered at fixed addresses.
+        if graph.roots().contains(&p) || KEEP_PARTS.contains(&part.name()) {
+            continue;
+        }
+        // Whole part consisting of a single jump.
+        if part.insns().len() == 1
+            && is_stub_jump(part.insn_at(0))
+            && graph.edges_to(p).all(|e| e.from == Some(p))
+        {
+            part.set_patch_delete_part();
+            removed += 1;
+            continue;
+        }
+        // Single jump blocks inside of the part that can't be reached anymore.
+        let cfg = Cfg::new(part);
+        for block in cfg.blocks().iter().skip(1) {
+            let insn = part.insn_at(block.start());
+            if block.range().len() == 1
+                && is_stub_jump(insn)
+                && block.preds().is_empty()
+                && !insn.label().is_some_and(|l| refs.contains(l))
+            {
+                part.insn_at_mut(block.start())
+                    .set_patch(Some(InsnPatch::empty()));
+                removed += 1;
+            }
+        }
+    }
+
+    println!("Jump threading: {retargeted} retargeted, {removed} stubs removed.");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{block_on, part_asm, program};
+
+    #[test]
+    fn test_thread() {
+        let mut program = program(
+            "atmega328p",
+            "
+__vectors:
+    jmp main
+main:
+    rcall a
+    breq a
+    rjmp main
+a:
+    rjmp b
+b:
+    ret
+",
+        );
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        assert_eq!(part_asm(&program, "main"), ["rcall b", "breq b", "rjmp main"]);
+        assert!(part_asm(&program, "a").is_empty());
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        // The final target can't be reached by rcall and rjmp.
+        let mut program = program(
+            "atmega328p",
+            &format!(
+                "
+__vectors:
+    jmp main
+main:
+    rcall a
+    rjmp a
+a:
+    jmp b
+{}b:
+    ret
+",
+                "    nop\n".repeat(2100)
+            ),
+        );
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        assert_eq!(part_asm(&program, "main"), ["rcall a", "rjmp a"]);
+        assert_eq!(part_asm(&program, "a")[0], "jmp b");
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
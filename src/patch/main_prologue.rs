@@ -2,7 +2,12 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 // Copyright (C) 2025 Michael Büsch <m@bues.ch>
 
-use crate::{abi::reg_is_callee_saved, program::{Insn, InsnPatch, PartPatch, Program}};
+use crate::{
+    abi::reg_is_callee_saved,
+    cfg::Cfg,
+    operand::Operand,
+    program::{Insn, InsnPatch, PartPatch, Program},
+};
 use anyhow::{self as ah, format_err as err};
 
 pub async fn run(program: &mut Program) -> ah::Result<()> {
@@ -18,9 +23,11 @@ pub async fn run(program: &mut Program) -> ah::Result<()> {
     for part in text.parts_mut() {
         // Patch the Rust main function.
         if part.demangled().ends_with("::__avr_device_rt_main") {
-            for insn in part.insns_mut() {
+            // The prologue is the leading push sequence of the entry block.
+            let entry = Cfg::new(part).entry().map(|b| b.range()).unwrap_or_default();
+            for insn in &mut part.insns_mut()[entry] {
                 if insn.name() == "push" && insn.ops().len() == 1 {
-                    if reg_is_callee_saved(&insn.ops()[0][..]) {
+                    if insn.ops()[0].reg().is_some_and(reg_is_callee_saved) {
                         // This push is part of the callee-save prologue.
                         // This is not needed in the main function.
                         // Remove it.
@@ -38,17 +45,17 @@ pub async fn run(program: &mut Program) -> ah::Result<()> {
 
     for part in text.parts_mut() {
         if !c_entry_patched &&
-            part.insns().len() >= 1 &&
+            !part.insns().is_empty() &&
             [ "rcall", "call" ].contains(&part.insns()[0].name()) &&
             part.insns()[0].ops().len() == 1 &&
-            part.insns()[0].ops()[0] == "main"
+            part.insns()[0].ops()[0].label() == Some("main")
         {
             // This is the entry from the C-rt init.
             // Directly jump to the Rust main.
             let mut new_part = part.clone_empty();
             new_part.add_insn(Insn::new(
                 "rjmp",
-                vec![rust_main_name.clone().unwrap()],
+                vec![Operand::Label(rust_main_name.clone().unwrap())],
                 None,
                 0,
             ));
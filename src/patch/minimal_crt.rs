@@ -0,0 +1,415 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+use crate::{
+    callgraph::{CallGraph, VECTORS},
+    devices::device_sp_reset_is_ramend,
+    insninfo::{Flow, sreg},
+    layout::DATA_LABEL,
+    operand::{Expr, ExprFunc, Operand, PtrMode, PtrReg},
+    program::{Insn, InsnPatch, Part, PartPatch, Program},
+};
+use anyhow::{self as ah, format_err as err};
+
+/// I/O addresses of the stack pointer.
+const SPL_IO: u8 = 0x3d;
+const SPH_IO: u8 = 0x3e;
+
+/// Maximum number of bytes that are copied or cleared without a loop.
+/// Up to this size the unrolled sequence is smaller than the loop.
+const MAX_UNROLLED: u32 = 2;
+
+fn insn(name: &str, ops: Vec<Operand>) -> Insn {
+    Insn::new(name, ops, None, 0)
+}
+
+fn ldi(reg: u8, value: u32) -> Insn {
+    insn("ldi", vec![Operand::Reg(reg), Operand::Imm((value & 0xFF) as i32)])
+}
+
+/// Get the constant that is loaded into `reg` by an ldi.
+fn ldi_value(insns: &[Insn], reg: u8) -> Option<u32> {
+    insns
+        .iter()
+        .find(|i| i.name() == "ldi" && i.ops()[0].reg() == Some(reg))
+        .and_then(|i| i.ops()[1].imm())
+        .map(|v| v as u32 & 0xFF)
+}
+
+/// Split a crt loop part behind the `brne` that closes the loop.
+/// The instructions behind the loop belong to the following init sections.
+fn split_loop(part: &Part) -> Option<(&[Insn], &[Insn])> {
+    let end = part.insns().iter().position(|i| i.name() == "brne")?;
+    Some(part.insns().split_at(end + 1))
+}
+
+/// Parse the (start, end) addresses of a crt loop over the register pair `lo`:
+/// `ldi lo, lo8(start); ldi lo+1, hi8(start); ...; cpi lo, lo8(end); cpc lo+1, rN`
+/// with rN loaded with hi8(end).
+fn loop_bounds(insns: &[Insn], lo: u8) -> Option<(u32, u32)> {
+    let hi = lo + 1;
+    let start = ldi_value(insns, lo)? | ldi_value(insns, hi)? << 8;
+    let find = |name: &str, reg: u8| {
+        insns
+            .iter()
+            .find(|i| i.name() == name && i.ops()[0].reg() == Some(reg))
+    };
+    let end_lo = find("cpi", lo)?.ops()[1].imm()? as u32 & 0xFF;
+    let end_hi = ldi_value(insns, find("cpc", hi)?.ops()[1].reg()?)?;
+    Some((start, end_lo | end_hi << 8))
+}
+
+/// Loop that runs `body` for X from `start` to `end`.
+/// The loop runs at least once.
+fn x_loop(label: &str, start: u32, end: u32, hi_reg: u8, mut body: Vec<Insn>) -> Vec<Insn> {
+    let mut insns = vec![ldi(26, start), ldi(27, start >> 8)];
+    // Comparing the low byte is sufficient,
+    // if it doesn't reach the end value before the end.
+    let short = end - start <= 256;
+    if !short {
+        insns.push(ldi(hi_reg, end >> 8));
+    }
+    body[0].set_label(Some(label.to_string()));
+    insns.extend(body);
+    insns.push(insn("cpi", vec![Operand::Reg(26), Operand::Imm((end & 0xFF) as i32)]));
+    if !short {
+        insns.push(insn("cpc", vec![Operand::Reg(27), Operand::Reg(hi_reg)]));
+    }
+    insns.push(insn("brne", vec![Operand::label_op(label)]));
+    insns
+}
+
+/// Copy the .data load image from flash to RAM at `start`.
+/// Returns `None`, if the original copy code must be kept.
+fn copy_data(start: u32, data: &[u8], elpm: bool) -> Option<Vec<Insn>> {
+    let size = data.len() as u32;
+    if size <= MAX_UNROLLED {
+        return Some(
+            data.iter()
+                .enumerate()
+                .flat_map(|(i, &byte)| {
+                    [
+                        ldi(24, byte.into()),
+                        insn("sts", vec![Operand::Data(start + i as u32), Operand::Reg(24)]),
+                    ]
+                })
+                .collect(),
+        );
+    }
+    if elpm {
+        // The load address has a third byte in RAMPZ.
+        return None;
+    }
+    let data_addr = |func| Operand::Expr(Expr::new(func, DATA_LABEL, false));
+    let mut insns = vec![
+        insn("ldi", vec![Operand::Reg(30), data_addr(ExprFunc::Lo8)]),
+        insn("ldi", vec![Operand::Reg(31), data_addr(ExprFunc::Hi8)]),
+    ];
+    insns.extend(x_loop(
+        "__crt_copy_data_loop",
+        start,
+        start + size,
+        17,
+        vec![
+            insn("lpm", vec![Operand::Reg(0), Operand::Ptr(PtrReg::Z, PtrMode::PostInc)]),
+            insn("st", vec![Operand::Ptr(PtrReg::X, PtrMode::PostInc), Operand::Reg(0)]),
+        ],
+    ));
+    Some(insns)
+}
+
+/// Clear the .bss area from `start` to `end` with the zero register.
+fn clear_bss(start: u32, end: u32) -> Vec<Insn> {
+    if end - start <= MAX_UNROLLED {
+        return (start..end)
+            .map(|addr| insn("sts", vec![Operand::Data(addr), Operand::Reg(1)]))
+            .collect();
+    }
+    x_loop(
+        "__crt_clear_bss_loop",
+        start,
+        end,
+        18,
+        vec![insn(
+            "st",
+            vec![Operand::Ptr(PtrReg::X, PtrMode::PostInc), Operand::Reg(1)],
+        )],
+    )
+}
+
+/// Remove the SREG and stack pointer initialization from the init code,
+/// if it only sets up the reset state.
+fn minimal_init(part: &Part, ramend: u32) -> Vec<Insn> {
+    let insns = part.insns();
+    let is_out = |insn: &Insn, io: u8| insn.name() == "out" && insn.ops()[0].io() == Some(io);
+
+    let mut remove = vec![false; insns.len()];
+    for (i, insn) in insns.iter().enumerate() {
+        // SREG is zero after reset.
+        if is_out(insn, sreg::IO) && insn.ops()[1].reg() == Some(1) {
+            remove[i] = true;
+        }
+        // SP is RAMEND after reset.
+        for (io, value) in [(SPL_IO, ramend & 0xFF), (SPH_IO, ramend >> 8)] {
+            if is_out(insn, io)
+                && let Some(reg) = insn.ops()[1].reg()
+                && ldi_value(insns, reg) == Some(value)
+            {
+                remove[i] = true;
+            }
+        }
+    }
+    // Remove the loads of the stack pointer value, if they are not used otherwise.
+    for (i, insn) in insns.iter().enumerate() {
+        if insn.name() == "ldi"
+            && let Some(reg) = insn.ops()[0].reg()
+            && insns
+                .iter()
+                .enumerate()
+                .filter(|&(j, other)| j != i && other.reg_uses().contains(reg))
+                .all(|(j, _)| remove[j])
+            && insns
+                .iter()
+                .enumerate()
+                .any(|(j, other)| remove[j] && other.reg_uses().contains(reg))
+        {
+            remove[i] = true;
+        }
+    }
+
+    insns
+        .iter()
+        .zip(remove)
+        .filter(|(_, remove)| !remove)
+        .map(|(insn, _)| insn.clone())
+        .collect()
+}
+
+/// Replace the instructions of a part.
+/// An empty replacement deletes the part.
+fn replace_part(part: &mut Part, insns: Vec<Insn>) {
+    let mut new_part = part.clone_empty();
+    for insn in insns {
+        new_part.add_insn(insn);
+    }
+    part.set_patch(Some(PartPatch::new(new_part)));
+}
+
+fn part_size(insns: &[Insn]) -> u32 {
+    insns.iter().map(|i| i.size_words() * 2).sum()
+}
+
+pub async fn run(program: &mut Program) -> ah::Result<()> {
+    let Some(device) = program.device() else {
+        return Err(err!("No device info."));
+    };
+    let ramend = device.sram_start + device.sram_size - 1;
+    let sp_reset_ramend = device_sp_reset_is_ramend(device);
+    let data = program
+        .section_data()
+        .map(|d| d.data().to_vec())
+        .unwrap_or_default();
+    let Some(text) = program.section_text_mut() else {
+        return Err(err!("Text section not found."));
+    };
+    text.apply_patches()?;
+    let graph = CallGraph::new(program);
+    let text = program.section_text_mut().unwrap();
+
+    // A jump to the reset vector from the program (e.g. from __bad_interrupt)
+    // restarts the init code without the hardware reset state.
+    let soft_reset = text
+        .parts()
+        .iter()
+        .position(|p| p.name() == VECTORS)
+        .is_some_and(|v| graph.edges_to(v).any(|e| e.from != Some(v)));
+
+    // The entry stub `call main; jmp _exit`.
+    // If main doesn't return, jump to it instead.
+    if text.find_part("main").is_some_and(|p| p.is_noreturn()) {
+        for part in text.parts_mut() {
+            let Some(i) = part.insns().iter().position(|i| {
+                i.flow() == Flow::Call && i.ops()[0].label() == Some("main")
+            }) else {
+                continue;
+            };
+            println!("  {}: main does not return", part.name());
+            let jmp = insn("rjmp", vec![Operand::label_op("main")]);
+            part.insn_at_mut(i).set_patch(Some(InsnPatch::new(vec![jmp])));
+            if let Some(next) = part.insns_mut().get_mut(i + 1)
+                && next.flow() == Flow::Jump
+                && next.ops()[0].label() == Some("_exit")
+            {
+                next.set_patch(Some(InsnPatch::empty()));
+            }
+        }
+    }
+    text.apply_patches()?;
+
+    let mut saved: i64 = 0;
+    let mut replace = |part: &mut Part, insns: Vec<Insn>| {
+        saved += part_size(part.insns()) as i64 - part_size(&insns) as i64;
+        replace_part(part, insns);
+    };
+
+    println!("Minimal C runtime:");
+    if let Some(part) = text.find_part_mut("__ctors_end") {
+        if !sp_reset_ramend {
+            println!("  __ctors_end: Stack pointer reset value unknown. Keeping SREG/SP init.");
+        } else if soft_reset {
+            println!("  __ctors_end: Reset vector is jumped to. Keeping SREG/SP init.");
+        } else {
+            let insns = minimal_init(part, ramend);
+            replace(part, insns);
+        }
+    }
+
+    if let Some(part) = text.find_part_mut("__do_copy_data") {
+        let elpm = part.insns().iter().any(|i| i.name() == "elpm");
+        match split_loop(part).and_then(|(l, tail)| Some((loop_bounds(l, 26)?, tail.to_vec()))) {
+            Some(((start, end), tail)) if end.checked_sub(start) == Some(data.len() as u32) => {
+                match copy_data(start, &data, elpm) {
+                    Some(mut insns) => {
+                        println!("  __do_copy_data: {} bytes", data.len());
+                        insns.extend(tail);
+                        replace(part, insns);
+                    }
+                    None => println!("  __do_copy_data: Keeping the RAMPZ copy loop."),
+                }
+            }
+            _ => println!("  __do_copy_data: Unknown code. Not changed."),
+        }
+    }
+
+    if let Some(part) = text.find_part_mut("__do_clear_bss") {
+        match split_loop(part).and_then(|(l, tail)| Some((loop_bounds(l, 26)?, tail.to_vec()))) {
+            Some(((start, end), tail)) if start <= end => {
+                println!("  __do_clear_bss: {} bytes", end - start);
+                let mut insns = clear_bss(start, end);
+                insns.extend(tail);
+                replace(part, insns);
+            }
+            _ => println!("  __do_clear_bss: Unknown code. Not changed."),
+        }
+    }
+
+    if let Some(part) = text.find_part_mut("__do_global_ctors") {
+        // The loop runs backwards from __ctors_end to __ctors_start.
+        match split_loop(part).and_then(|(l, tail)| Some((loop_bounds(l, 28)?, tail.to_vec()))) {
+            Some(((end, start), tail)) if end == start => {
+                println!("  __do_global_ctors: No constructors");
+                replace(part, tail);
+            }
+            Some(_) => println!("  __do_global_ctors: Constructors present. Not changed."),
+            None => println!("  __do_global_ctors: Unknown code. Not changed."),
+        }
+    }
+
+    println!("Minimal C runtime: {saved} bytes saved.");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{block_on, part_asm, program_with_data};
+
+    /// C runtime with 2 bytes of .data at 0x100 and `bss` bytes of .bss behind it.
+    fn crt(bad_interrupt: &str, bss: u32, data: &[u8]) -> Program {
+        let bss_end = 0x102 + bss;
+        let asm = format!(
+            "
+__vectors:
+    jmp __ctors_end
+    jmp __bad_interrupt
+__ctors_end:
+    eor r1, r1
+    out 0x3f, r1
+    ldi r28, 0xFF
+    ldi r29, 0x08
+    out 0x3e, r29
+    out 0x3d, r28
+__do_copy_data:
+    ldi r17, 0x01
+    ldi r26, 0x00
+    ldi r27, 0x01
+    ldi r30, 0x00
+    ldi r31, 0x10
+    rjmp l1
+    l0: lpm r0, Z+
+    st X+, r0
+    l1: cpi r26, 0x02
+    cpc r27, r17
+    brne l0
+__do_clear_bss:
+    ldi r18, 0x{:02X}
+    ldi r26, 0x02
+    ldi r27, 0x01
+    rjmp l3
+    l2: st X+, r1
+    l3: cpi r26, 0x{:02X}
+    cpc r27, r18
+    brne l2
+__call_main:
+    call main
+    jmp _exit
+__bad_interrupt:
+{bad_interrupt}
+main:
+    rjmp main
+_exit:
+    cli
+    l4: rjmp l4
+",
+            bss_end >> 8,
+            bss_end & 0xFF
+        );
+        let mut program = program_with_data("atmega328p", &asm, data);
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        program
+    }
+
+    #[test]
+    fn test_minimal() {
+        let program = crt("    rjmp __bad_interrupt\n", 1, &[0x12, 0x34]);
+        assert_eq!(part_asm(&program, "__ctors_end"), ["eor r1, r1"]);
+        assert_eq!(
+            part_asm(&program, "__do_copy_data"),
+            ["ldi r24, 0x12", "sts 0x0100, r24", "ldi r24, 0x34", "sts 0x0101, r24"]
+        );
+        assert_eq!(part_asm(&program, "__do_clear_bss"), ["sts 0x0102, r1"]);
+    }
+
+    #[test]
+    fn test_loops() {
+        let program = crt("    rjmp __bad_interrupt\n", 0x200, &[0x12, 0x34]);
+        assert_eq!(
+            part_asm(&program, "__do_clear_bss"),
+            [
+                "ldi r26, 0x02",
+                "ldi r27, 0x01",
+                "ldi r18, 0x03",
+                "__crt_clear_bss_loop: st X+, r1",
+                "cpi r26, 0x02",
+                "cpc r27, r18",
+                "brne __crt_clear_bss_loop",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keep() {
+        // Jumps to the reset vector need the SREG and SP init.
+        let program = crt("    jmp __vectors\n", 1, &[0x12, 0x34]);
+        assert_eq!(part_asm(&program, "__ctors_end").len(), 6);
+        assert_eq!(part_asm(&program, "__do_clear_bss"), ["sts 0x0102, r1"]);
+        // The .data size doesn't match the copy loop.
+        let program = crt("    rjmp __bad_interrupt\n", 1, &[0x12, 0x34, 0x56]);
+        assert_eq!(part_asm(&program, "__do_copy_data").len(), 11);
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -0,0 +1,297 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+use crate::{
+    callgraph::{CallGraph, number_addrs},
+    cfg::{Cfg, EdgeTarget},
+    insninfo::Flow,
+    operand::Operand,
+    program::{CodeSection, InsnPatch, Program},
+};
+use anyhow::{self as ah, format_err as err};
+use std::collections::{HashMap, HashSet};
+
+/// Parts that are entered through addresses computed at run time.
+const KEEP_PARTS: &[&str] = &["__trampolines_start"];
+
+/// Reachable instructions of a part.
+struct Reach {
+    /// Reachability by instruction index.
+    insns: Vec<bool>,
+    /// Some path leaves the part to a destination that returns.
+    returns: bool,
+}
+
+/// Walk the CFG of the part `p` from the instructions at `entries`.
+/// Calls to parts in `noreturn` end the path.
+fn reach(
+    text: &CodeSection,
+    p: usize,
+    labels: &HashMap<String, usize>,
+    noreturn: &[bool],
+    entries: &[usize],
+) -> Reach {
+    let part = text.part_at(p);
+    let insns = part.insns();
+    let cfg = Cfg::new(part);
+    let mut reach = Reach {
+        insns: vec![false; insns.len()],
+        returns: false,
+    };
+    if insns.is_empty() {
+        return reach;
+    }
+    let part_returns = |label: &str| labels.get(label).is_none_or(|&to| !noreturn[to]);
+
+    let mut visited = vec![false; cfg.blocks().len()];
+    let mut work: Vec<usize> = entries.iter().map(|&i| cfg.block_of(i)).collect();
+    'blocks: while let Some(b) = work.pop() {
+        if visited[b] {
+            continue;
+        }
+        visited[b] = true;
+        let block = cfg.block(b);
+        for i in block.range() {
+            reach.insns[i] = true;
+            let insn = &insns[i];
+            if insn.flow() == Flow::Call
+                && let Some(label) = insn.ops().last().and_then(|op| op.label())
+                && !part_returns(label)
+            {
+                continue 'blocks;
+            }
+            if insn.flow() == Flow::Return {
+                reach.returns = true;
+            }
+        }
+        for edge in block.succs() {
+            match &edge.target {
+                EdgeTarget::Block(succ) => work.push(*succ),
+                EdgeTarget::Label(label) => reach.returns |= part_returns(label),
+                EdgeTarget::NextPart => {
+                    let next = (p + 1..text.parts().len())
+                        .find(|&n| !text.part_at(n).insns().is_empty());
+                    reach.returns |= next.is_none_or(|n| !noreturn[n]);
+                }
+                EdgeTarget::Unknown => reach.returns = true,
+            }
+        }
+    }
+    reach
+}
+
+pub async fn run(program: &mut Program) -> ah::Result<()> {
+    let Some(text) = program.section_text_mut() else {
+        return Err(err!("Text section not found."));
+    };
+    text.apply_patches()?;
+    let graph = CallGraph::new(program);
+    let numbers = number_addrs(program);
+    let text = program.section_text_mut().unwrap();
+    let count = text.parts().len();
+
+    // Map all labels to their part.
+    let mut labels = HashMap::new();
+    for (p, part) in text.parts().iter().enumerate() {
+        labels.insert(part.name().to_string(), p);
+        for insn in part.insns() {
+            if let Some(label) = insn.label() {
+                labels.insert(label.to_string(), p);
+            }
+        }
+    }
+
+    // Labels that can be entered other than through the control flow of their part:
+    // References from other parts and address references.
+    let mut entry_labels = HashSet::new();
+    for part in text.parts() {
+        let own: HashSet<&str> = part.insns().iter().filter_map(|i| i.label()).collect();
+        for insn in part.insns() {
+            for op in insn.ops() {
+                match op {
+                    Operand::Label(label) if !own.contains(label.as_str()) => {
+                        entry_labels.insert(label.clone());
+                    }
+                    Operand::Expr(expr) => {
+                        entry_labels.insert(expr.label.clone());
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+    let entries: Vec<Vec<usize>> = text
+        .parts()
+        .iter()
+        .map(|part| {
+            let labeled = part.insns().iter().enumerate().filter_map(|(i, insn)| {
+                insn.label()
+                    .is_some_and(|l| entry_labels.contains(l))
+                    .then_some(i)
+            });
+            std::iter::once(0).chain(labeled).collect()
+        })
+        .collect();
+
+    // Start with all parts not returning and iterate until no more returning parts are found.
+    let mut noreturn: Vec<bool> = text.parts().iter().map(|p| !p.insns().is_empty()).collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for p in 0..count {
+            if noreturn[p] && reach(text, p, &labels, &noreturn, &entries[p]).returns {
+                noreturn[p] = false;
+                changed = true;
+            }
+        }
+    }
+
+    // Remove the unreachable instructions.
+    let mut removed = 0;
+    let mut saved = 0;
+    println!("No-return functions:");
+    for p in 0..count {
+        if noreturn[p] {
+            println!("  {}", text.part_at(p).name());
+        }
+        if graph.roots().contains(&p) || KEEP_PARTS.contains(&text.part_at(p).name()) {
+            continue;
+        }
+        // Computed jumps (e.g. switch jump tables) and code addresses stored as
+        // plain numbers may enter the part at any instruction.
+        let part = text.part_at(p);
+        let indirect = Cfg::new(part)
+            .blocks()
+            .iter()
+            .flat_map(|b| b.succs())
+            .any(|e| matches!(e.target, EdgeTarget::Unknown));
+        if indirect || part.insns().iter().any(|i| numbers.contains(&(i.addr() / 2))) {
+            continue;
+        }
+        let reached = reach(text, p, &labels, &noreturn, &entries[p]).insns;
+        let part = text.part_at_mut(p);
+        for (i, reached) in reached.into_iter().enumerate() {
+            let insn = part.insn_at_mut(i);
+            // Keep data in the text section.
+            if !reached && !insn.name().starts_with('.') {
+                saved += insn.size_words() * 2;
+                insn.set_patch(Some(InsnPatch::empty()));
+                removed += 1;
+            }
+        }
+    }
+    for (p, &noreturn) in noreturn.iter().enumerate() {
+        text.part_at_mut(p).set_noreturn(noreturn);
+    }
+    println!(
+        "No-return: {} functions, {removed} unreachable instructions removed, {saved} bytes saved.",
+        noreturn.iter().filter(|&&n| n).count()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{block_on, part_asm, program, program_with_data};
+
+    fn noreturn(mut program: Program) -> Program {
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        program
+    }
+
+    #[test]
+    fn test_unreachable_after_noreturn_call() {
+        let program = noreturn(program(
+            "atmega328p",
+            "
+__vectors:
+    jmp main
+main:
+    rcall f
+    rjmp main
+f:
+    rcall stop
+    ldi r24, 0x01
+    ret
+stop:
+    rjmp stop
+",
+        ));
+        assert_eq!(part_asm(&program, "f"), ["rcall stop"]);
+        assert_eq!(part_asm(&program, "stop"), ["rjmp stop"]);
+        let text = program.section_text().unwrap();
+        assert!(text.find_part("stop").unwrap().is_noreturn());
+        assert!(text.find_part("f").unwrap().is_noreturn());
+    }
+
+    #[test]
+    fn test_jump_table() {
+        let program = noreturn(program(
+            "atmega328p",
+            "
+__vectors:
+    jmp main
+main:
+    rcall func
+    rjmp main
+func:
+    ldi r30, 0x07
+    ldi r31, 0x00
+    ijmp
+    ldi r24, 0x01
+    ret
+",
+        ));
+        assert_eq!(part_asm(&program, "func").len(), 5);
+    }
+
+    #[test]
+    fn test_computed_goto() {
+        // The jump target is not relocated, because it's copied to Z with mov.
+        let program = noreturn(program(
+            "atmega328p",
+            "
+__vectors:
+    jmp main
+main:
+    rcall func
+    rjmp main
+func:
+    ldi r24, 0x09
+    ldi r25, 0x00
+    mov r30, r24
+    mov r31, r25
+    ijmp
+    ldi r24, 0x01
+    ret
+",
+        ));
+        assert_eq!(part_asm(&program, "func")[5], "ldi r24, 0x01");
+    }
+
+    #[test]
+    fn test_data_reference() {
+        let program = noreturn(program_with_data(
+            "atmega328p",
+            "
+__vectors:
+    jmp main
+main:
+    rcall f
+    rjmp main
+f:
+    ret
+    ldi r24, 0x01
+    ret
+",
+            &[0x05, 0x00],
+        ));
+        assert_eq!(part_asm(&program, "f"), ["ret", "ldi r24, 0x01", "ret"]);
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -0,0 +1,214 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+use crate::{opcodes::parse_num, operand::is_label};
+use anyhow::{self as ah, format_err as err};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// Type of a patch step parameter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParamType {
+    /// Assembler symbol name.
+    Symbol,
+    /// File system path.
+    Path,
+    /// Unsigned integer.
+    Uint,
+}
+
+impl ParamType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Symbol => "SYMBOL",
+            Self::Path => "PATH",
+            Self::Uint => "UINT",
+        }
+    }
+
+    fn parse(&self, value: &str) -> ah::Result<ParamValue> {
+        match self {
+            Self::Symbol => {
+                if !is_label(value) {
+                    return Err(err!("'{value}' is not a valid symbol name."));
+                }
+                Ok(ParamValue::Symbol(value.to_string()))
+            }
+            Self::Path => {
+                if value.is_empty() {
+                    return Err(err!("Empty path."));
+                }
+                Ok(ParamValue::Path(value.into()))
+            }
+            Self::Uint => match parse_num(value).and_then(|v| u32::try_from(v).ok()) {
+                Some(v) => Ok(ParamValue::Uint(v)),
+                None => Err(err!("'{value}' is not an unsigned integer.")),
+            },
+        }
+    }
+}
+
+/// Declaration of a patch step parameter.
+#[derive(Clone, Debug)]
+pub struct ParamDesc {
+    pub name: &'static str,
+    pub ty: ParamType,
+    /// Default value. The parameter is mandatory, if there is no default.
+    pub default: Option<&'static str>,
+    pub help: &'static str,
+}
+
+#[derive(Clone, Debug)]
+enum ParamValue {
+    Symbol(String),
+    Path(PathBuf),
+    Uint(u32),
+}
+
+/// Parameter values of a patch step.
+#[derive(Clone, Debug)]
+pub struct Params {
+    values: HashMap<&'static str, ParamValue>,
+}
+
+impl Params {
+    /// Parse the parameters `key=value,key=value` of a step.
+    pub fn parse(descs: &[ParamDesc], text: Option<&str>) -> ah::Result<Self> {
+        let mut values = HashMap::new();
+        for param in text.into_iter().flat_map(|t| t.split(',')) {
+            let Some((key, value)) = param.split_once('=') else {
+                return Err(err!("Parameter '{param}' is not in the form name=value."));
+            };
+            let key = key.trim();
+            let Some(desc) = descs.iter().find(|d| d.name == key) else {
+                if descs.is_empty() {
+                    return Err(err!("The step does not take parameters."));
+                }
+                return Err(err!(
+                    "Unknown parameter '{key}'. Known parameters: {}",
+                    descs.iter().map(|d| d.name).collect::<Vec<_>>().join(", ")
+                ));
+            };
+            let value = desc
+                .ty
+                .parse(value.trim())
+                .map_err(|e| err!("Parameter '{key}': {e}"))?;
+            if values.insert(desc.name, value).is_some() {
+                return Err(err!("Parameter '{key}' is given more than once."));
+            }
+        }
+        for desc in descs {
+            if values.contains_key(desc.name) {
+                continue;
+            }
+            let Some(default) = desc.default else {
+                return Err(err!("Missing parameter '{}'.", desc.name));
+            };
+            values.insert(desc.name, desc.ty.parse(default)?);
+        }
+        Ok(Self { values })
+    }
+
+    fn get(&self, name: &str) -> &ParamValue {
+        self.values
+            .get(name)
+            .unwrap_or_else(|| panic!("Undeclared parameter '{name}'."))
+    }
+
+    pub fn symbol(&self, name: &str) -> &str {
+        match self.get(name) {
+            ParamValue::Symbol(v) => v,
+            v => panic!("Parameter '{name}' is not a symbol: {v:?}"),
+        }
+    }
+
+    pub fn path(&self, name: &str) -> &Path {
+        match self.get(name) {
+            ParamValue::Path(v) => v,
+            v => panic!("Parameter '{name}' is not a path: {v:?}"),
+        }
+    }
+
+    pub fn uint(&self, name: &str) -> u32 {
+        match self.get(name) {
+            ParamValue::Uint(v) => *v,
+            v => panic!("Parameter '{name}' is not an unsigned integer: {v:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCS: &[ParamDesc] = &[
+        ParamDesc {
+            name: "target",
+            ty: ParamType::Symbol,
+            default: None,
+            help: "",
+        },
+        ParamDesc {
+            name: "passes",
+            ty: ParamType::Uint,
+            default: Some("4"),
+            help: "",
+        },
+    ];
+
+    fn parse_err(descs: &[ParamDesc], text: &str) -> String {
+        Params::parse(descs, Some(text)).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_parse() {
+        let params = Params::parse(DESCS, Some("target=_exit")).unwrap();
+        assert_eq!(params.symbol("target"), "_exit");
+        assert_eq!(params.uint("passes"), 4);
+        let params = Params::parse(DESCS, Some(" passes = 0x10 , target=f")).unwrap();
+        assert_eq!(params.uint("passes"), 16);
+        assert!(Params::parse(&[], None).is_ok());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            Params::parse(DESCS, None).unwrap_err().to_string(),
+            "Missing parameter 'target'."
+        );
+        assert_eq!(
+            parse_err(DESCS, "target=f,foo=1"),
+            "Unknown parameter 'foo'. Known parameters: target, passes"
+        );
+        assert_eq!(
+            parse_err(DESCS, "target=f,passes=-1"),
+            "Parameter 'passes': '-1' is not an unsigned integer."
+        );
+        assert_eq!(
+            parse_err(DESCS, "target=1f"),
+            "Parameter 'target': '1f' is not a valid symbol name."
+        );
+        assert_eq!(
+            parse_err(DESCS, "target=a.b"),
+            "Parameter 'target': 'a.b' is not a valid symbol name."
+        );
+        assert_eq!(
+            parse_err(DESCS, "target=$x"),
+            "Parameter 'target': '$x' is not a valid symbol name."
+        );
+        assert_eq!(
+            parse_err(DESCS, "target=f,target=g"),
+            "Parameter 'target' is given more than once."
+        );
+        assert_eq!(
+            parse_err(DESCS, "target"),
+            "Parameter 'target' is not in the form name=value."
+        );
+        assert_eq!(parse_err(&[], "a=1"), "The step does not take parameters.");
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -0,0 +1,118 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+use crate::{
+    callgraph::VECTORS,
+    devices::device_vector_words,
+    layout::{Layout, rel_in_range},
+    program::{InsnPatch, Program},
+};
+use anyhow::{self as ah, format_err as err};
+
+pub async fn run(program: &mut Program) -> ah::Result<()> {
+    let Some(device) = program.device() else {
+        return Err(err!("No device info."));
+    };
+    let flash_size = device.flash_size;
+    let vector_words = device_vector_words(device);
+
+    let mut relaxed = 0;
+    let mut saved = 0;
+
+    // Each shrunk instruction may bring more targets into range.
+    // Iterate until nothing changes.
+    loop {
+        let Some(text) = program.section_text_mut() else {
+            return Err(err!("Text section not found."));
+        };
+        text.apply_patches()?;
+        let layout = Layout::new(program)?;
+        let text = program.section_text_mut().unwrap();
+
+        let mut changed = false;
+        let mut addr = 0;
+        for part in text.parts_mut() {
+            // The trampoline entries are addressed by gs() and keep their size.
+            // The vector table entries keep their size, unless they are one word,
+            // so there's nothing to gain in there.
+            let keep = part.name() == "__trampolines_start"
+                || (part.name() == VECTORS && vector_words != 1);
+            for i in 0..part.insns().len() {
+                // The skip instruction shadow must keep its size.
+                let in_shadow = part.in_skip_shadow(i);
+                let insn = part.insn_at_mut(i);
+                let size = insn.size_words();
+                let short = match insn.name() {
+                    "call" => "rcall",
+                    "jmp" => "rjmp",
+                    _ => {
+                        addr += size;
+                        continue;
+                    }
+                };
+                if !keep
+                    && !in_shadow
+                    && let Some(target) = insn.ops()[0].label()
+                    && rel_in_range(short, addr, layout.resolve(target)?, flash_size)
+                {
+                    let mut short_insn = insn.clone();
+                    short_insn.set_name(short);
+                    insn.set_patch(Some(InsnPatch::new(vec![short_insn])));
+                    saved += 2;
+                    relaxed += 1;
+                    changed = true;
+                }
+                addr += size;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    println!("Relaxed calls: {relaxed} relaxed, {saved} bytes saved.");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{block_on, part_asm, program};
+
+    #[test]
+    fn test_relax() {
+        let mut program = program(
+            "atmega328p",
+            &format!(
+                "
+__vectors:
+    jmp main
+    jmp main
+main:
+    call f
+    jmp main
+    sbrc r24, 0
+    call f
+    call far
+f:
+    ret
+{}far:
+    ret
+",
+                "    nop\n".repeat(2100)
+            ),
+        );
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        // Vector entries are two words on this device.
+        assert_eq!(part_asm(&program, "__vectors"), ["jmp main", "jmp main"]);
+        assert_eq!(
+            part_asm(&program, "main"),
+            ["rcall f", "rjmp main", "sbrc r24, 0", "call f", "call far"]
+        );
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
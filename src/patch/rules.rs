@@ -0,0 +1,676 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+//! Peephole rewrite rules loaded from a rule file.
+//!
+//! A rule file contains any number of rules:
+//!
+//! ```text
+//! # Comment
+//! rule <name>
+//!     <pattern instruction>
+//!     ...
+//! if <constraint>
+//! =>
+//!     <replacement instruction>
+//!     ...
+//! end
+//! ```
+//!
+//! The pattern matches consecutive instructions within a basic block.
+//! Operands in the pattern are either literals (`r24`, `0x3f`, `X+`)
+//! or metavariables:
+//!
+//! - `%name` matches a register,
+//! - `$name` matches a number (immediate, bit, I/O or data address),
+//! - `@name` matches a label.
+//!
+//! A metavariable that occurs more than once must match the same operand each time.
+//!
+//! Constraints:
+//!
+//! - `if <a> <op> <b>` compares two metavariables or numbers
+//!   with `==`, `!=`, `<`, `<=`, `>` or `>=`,
+//! - `if dead %name` requires the register to be dead after the matched sequence,
+//! - `if dead sreg` requires all SREG flags to be dead after the matched sequence.
+//!
+//! The replacement instructions may use the metavariables of the pattern.
+//! An empty replacement removes the matched sequence.
+//!
+//! Example:
+//!
+//! ```text
+//! rule ldi-ldi
+//!     ldi %a, $x
+//!     ldi %a, $y
+//! =>
+//!     ldi %a, $y
+//! end
+//! ```
+
+use crate::{
+    cfg::Cfg,
+    insninfo::{Flow, sreg},
+    liveness::Liveness,
+    opcodes::{insn_size_words, parse_insn_ops, parse_num},
+    operand::{Dialect, Operand},
+    patch::params::Params,
+    program::{Insn, InsnPatch, Part, Program},
+};
+use anyhow::{self as ah, Context as _, format_err as err};
+use std::collections::HashMap;
+
+/// Operand of a pattern instruction.
+#[derive(Clone, Debug)]
+enum PatternOp {
+    Reg(String),
+    Num(String),
+    Label(String),
+    Literal(String),
+}
+
+#[derive(Clone, Debug)]
+struct PatternInsn {
+    name: String,
+    ops: Vec<PatternOp>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug)]
+enum Value {
+    Var(String),
+    Num(i64),
+}
+
+#[derive(Clone, Debug)]
+enum Constraint {
+    Compare(Value, CmpOp, Value),
+    DeadReg(String),
+    DeadSreg,
+}
+
+/// Replacement instruction with the operands as text.
+#[derive(Clone, Debug)]
+struct TemplateInsn {
+    name: String,
+    ops: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    name: String,
+    pattern: Vec<PatternInsn>,
+    constraints: Vec<Constraint>,
+    replacement: Vec<TemplateInsn>,
+}
+
+type Bindings = HashMap<String, Operand>;
+
+fn is_var_name(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Split a metavariable into its sigil and name.
+fn parse_var(s: &str) -> Option<(char, &str)> {
+    let sigil = s.chars().next()?;
+    let name = &s[1..];
+    ("%$@".contains(sigil) && is_var_name(name)).then_some((sigil, name))
+}
+
+/// Split an instruction line into the mnemonic and the operands.
+fn split_insn(line: &str) -> (String, Vec<String>) {
+    let (name, ops) = line
+        .split_once(char::is_whitespace)
+        .unwrap_or((line, ""));
+    let ops = ops
+        .split(',')
+        .map(|op| op.trim().to_string())
+        .filter(|op| !op.is_empty())
+        .collect();
+    (name.to_lowercase(), ops)
+}
+
+fn parse_value(s: &str) -> ah::Result<Value> {
+    if parse_var(s).is_some() {
+        Ok(Value::Var(s.to_string()))
+    } else if let Some(n) = parse_num(s) {
+        Ok(Value::Num(n))
+    } else {
+        Err(err!("Invalid value '{s}'."))
+    }
+}
+
+fn parse_constraint(s: &str) -> ah::Result<Constraint> {
+    if let Some(what) = s.strip_prefix("dead ") {
+        let what = what.trim();
+        if what.eq_ignore_ascii_case("sreg") {
+            return Ok(Constraint::DeadSreg);
+        }
+        return match parse_var(what) {
+            Some(('%', _)) => Ok(Constraint::DeadReg(what.to_string())),
+            _ => Err(err!("'dead' needs a register metavariable or 'sreg'.")),
+        };
+    }
+    for (text, op) in [
+        ("==", CmpOp::Eq),
+        ("!=", CmpOp::Ne),
+        ("<=", CmpOp::Le),
+        (">=", CmpOp::Ge),
+        ("<", CmpOp::Lt),
+        (">", CmpOp::Gt),
+    ] {
+        if let Some((a, b)) = s.split_once(text) {
+            return Ok(Constraint::Compare(
+                parse_value(a.trim())?,
+                op,
+                parse_value(b.trim())?,
+            ));
+        }
+    }
+    Err(err!("Invalid constraint '{s}'."))
+}
+
+/// Check that all metavariables in `text` are bound by the pattern.
+fn check_vars_bound(text: &str, pattern: &[PatternInsn]) -> ah::Result<()> {
+    for var in template_vars(text) {
+        let bound = pattern.iter().flat_map(|p| &p.ops).any(|op| match op {
+            PatternOp::Reg(v) | PatternOp::Num(v) | PatternOp::Label(v) => *v == var,
+            PatternOp::Literal(_) => false,
+        });
+        if !bound {
+            return Err(err!("Metavariable '{var}' is not bound by the pattern."));
+        }
+    }
+    Ok(())
+}
+
+/// All metavariables in a text.
+fn template_vars(text: &str) -> Vec<String> {
+    let mut vars = vec![];
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if "%$@".contains(c) {
+            let mut end = start + 1;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                end = i + 1;
+                chars.next();
+            }
+            if end > start + 1 {
+                vars.push(text[start..end].to_string());
+            }
+        }
+    }
+    vars
+}
+
+fn parse_rules(text: &str) -> ah::Result<Vec<Rule>> {
+    enum State {
+        Outside,
+        Pattern,
+        Replacement,
+    }
+
+    let mut rules = vec![];
+    let mut state = State::Outside;
+    let mut rule: Option<Rule> = None;
+
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or_default().trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let context = || format!("Line {}: '{line}'", lineno + 1);
+
+        match state {
+            State::Outside => {
+                let Some(name) = line.strip_prefix("rule ").map(str::trim) else {
+                    return Err(err!("Expected 'rule <name>'.")).with_context(context);
+                };
+                rule = Some(Rule {
+                    name: name.to_string(),
+                    pattern: vec![],
+                    constraints: vec![],
+                    replacement: vec![],
+                });
+                state = State::Pattern;
+            }
+            State::Pattern => {
+                let r = rule.as_mut().unwrap();
+                if line == "=>" {
+                    if r.pattern.is_empty() {
+                        return Err(err!("Empty pattern.")).with_context(context);
+                    }
+                    state = State::Replacement;
+                } else if let Some(constraint) = line.strip_prefix("if ") {
+                    let constraint = parse_constraint(constraint.trim()).with_context(context)?;
+                    r.constraints.push(constraint);
+                } else {
+                    let (name, ops) = split_insn(line);
+                    if insn_size_words(&name).is_none() {
+                        return Err(err!("Unknown instruction '{name}'.")).with_context(context);
+                    }
+                    let ops = ops
+                        .into_iter()
+                        .map(|op| match parse_var(&op) {
+                            Some(('%', _)) => PatternOp::Reg(op),
+                            Some(('$', _)) => PatternOp::Num(op),
+                            Some(_) => PatternOp::Label(op),
+                            None => PatternOp::Literal(op),
+                        })
+                        .collect();
+                    r.pattern.push(PatternInsn { name, ops });
+                }
+            }
+            State::Replacement => {
+                let r = rule.as_mut().unwrap();
+                if line == "end" {
+                    for c in &r.constraints {
+                        let vars = match c {
+                            Constraint::Compare(a, _, b) => [a, b]
+                                .into_iter()
+                                .filter_map(|v| match v {
+                                    Value::Var(v) => Some(v.clone()),
+                                    Value::Num(_) => None,
+                                })
+                                .collect(),
+                            Constraint::DeadReg(v) => vec![v.clone()],
+                            Constraint::DeadSreg => vec![],
+                        };
+                        check_vars_bound(&vars.join(" "), &r.pattern)
+                            .with_context(|| format!("Rule '{}'", r.name))?;
+                    }
+                    rules.push(rule.take().unwrap());
+                    state = State::Outside;
+                } else {
+                    let (name, ops) = split_insn(line);
+                    if insn_size_words(&name).is_none() {
+                        return Err(err!("Unknown instruction '{name}'.")).with_context(context);
+                    }
+                    check_vars_bound(line, &r.pattern).with_context(context)?;
+                    r.replacement.push(TemplateInsn { name, ops });
+                }
+            }
+        }
+    }
+    if let Some(rule) = rule {
+        return Err(err!("Rule '{}' is not terminated by 'end'.", rule.name));
+    }
+    Ok(rules)
+}
+
+/// Numeric value of an operand.
+fn num_value(op: &Operand) -> Option<i64> {
+    match op {
+        Operand::Reg(v) | Operand::RegPair(v) | Operand::Bit(v) | Operand::Io(v) => Some(*v as i64),
+        Operand::Imm(v) => Some(*v as i64),
+        Operand::Data(v) => Some(*v as i64),
+        _ => None,
+    }
+}
+
+/// Operand as text, as it can be parsed back by the instruction parser.
+fn op_text(op: &Operand) -> String {
+    match op {
+        Operand::Expr(expr) => expr.to_asm(Dialect::Gnu),
+        op => match num_value(op) {
+            Some(v) if !matches!(op, Operand::Reg(_) | Operand::RegPair(_)) => v.to_string(),
+            _ => op.to_string(),
+        },
+    }
+}
+
+fn same_op(a: &Operand, b: &Operand) -> bool {
+    match (num_value(a), num_value(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn bind(bindings: &mut Bindings, var: &str, op: &Operand) -> bool {
+    match bindings.get(var) {
+        Some(bound) => same_op(bound, op),
+        None => {
+            bindings.insert(var.to_string(), op.clone());
+            true
+        }
+    }
+}
+
+impl PatternInsn {
+    fn matches(&self, insn: &Insn, bindings: &mut Bindings) -> bool {
+        insn.name() == self.name
+            && insn.ops().len() == self.ops.len()
+            && self.ops.iter().zip(insn.ops()).all(|(pat, op)| match pat {
+                PatternOp::Reg(var) => {
+                    matches!(op, Operand::Reg(_) | Operand::RegPair(_)) && bind(bindings, var, op)
+                }
+                PatternOp::Num(var) => {
+                    matches!(
+                        op,
+                        Operand::Imm(_) | Operand::Bit(_) | Operand::Io(_) | Operand::Data(_)
+                    ) && bind(bindings, var, op)
+                }
+                PatternOp::Label(var) => op.label().is_some() && bind(bindings, var, op),
+                PatternOp::Literal(lit) => match (parse_num(lit), num_value(op)) {
+                    (Some(n), Some(v)) => n == v && !matches!(op, Operand::Reg(_)),
+                    _ => lit.eq_ignore_ascii_case(&op.to_string()),
+                },
+            })
+    }
+}
+
+impl Rule {
+    /// Check the constraints.
+    /// `live_out` is the liveness after the matched sequence.
+    fn check(&self, bindings: &Bindings, live_out: crate::liveness::LiveSet) -> bool {
+        let value = |v: &Value| match v {
+            Value::Var(var) => bindings.get(var).cloned(),
+            Value::Num(n) => Some(Operand::Imm(*n as i32)),
+        };
+        self.constraints.iter().all(|c| match c {
+            Constraint::Compare(a, op, b) => {
+                let (Some(a), Some(b)) = (value(a), value(b)) else {
+                    return false;
+                };
+                match (num_value(&a), num_value(&b), op) {
+                    (_, _, CmpOp::Eq) => same_op(&a, &b),
+                    (_, _, CmpOp::Ne) => !same_op(&a, &b),
+                    (Some(a), Some(b), CmpOp::Lt) => a < b,
+                    (Some(a), Some(b), CmpOp::Le) => a <= b,
+                    (Some(a), Some(b), CmpOp::Gt) => a > b,
+                    (Some(a), Some(b), CmpOp::Ge) => a >= b,
+                    _ => false,
+                }
+            }
+            Constraint::DeadReg(var) => bindings
+                .get(var)
+                .and_then(num_value)
+                .is_some_and(|r| !live_out.regs.contains(r as u8)),
+            Constraint::DeadSreg => live_out.sreg & sreg::ALL == 0,
+        })
+    }
+
+    /// Instantiate the replacement with the bound metavariables.
+    fn replacement(&self, bindings: &Bindings, addr: u32) -> ah::Result<Vec<Insn>> {
+        let mut insns = vec![];
+        for tmpl in &self.replacement {
+            let ops: Vec<String> = tmpl
+                .ops
+                .iter()
+                .map(|op| {
+                    let mut op = op.clone();
+                    // Longest names first, so that %a doesn't replace a part of %ab.
+                    let mut vars = template_vars(&op);
+                    vars.sort_by_key(|v| std::cmp::Reverse(v.len()));
+                    for var in vars {
+                        op = op.replace(&var, &op_text(&bindings[&var]));
+                    }
+                    op
+                })
+                .collect();
+            let refs: Vec<&str> = ops.iter().map(|s| s.as_str()).collect();
+            let ops = parse_insn_ops(&tmpl.name, &refs)
+                .with_context(|| format!("Rule '{}'", self.name))?;
+            insns.push(Insn::new(&tmpl.name, ops, None, addr));
+        }
+        Ok(insns)
+    }
+}
+
+/// Apply the rules once to all non-overlapping matches in the part.
+/// Returns the number of applied rules per rule index.
+fn apply_rules(part: &mut Part, rules: &[Rule], counts: &mut [usize]) -> ah::Result<usize> {
+    let cfg = Cfg::new(part);
+    let liveness = Liveness::new(part.insns(), &cfg);
+    let insns = part.insns();
+
+    let mut patches = vec![];
+    let mut i = 0;
+    'insns: while i < insns.len() {
+        // Patches in the skip shadow must keep the length. Don't touch it.
+        if part.in_skip_shadow(i) {
+            i += 1;
+            continue;
+        }
+        for (r, rule) in rules.iter().enumerate() {
+            let n = rule.pattern.len();
+            if i + n > insns.len()
+                || (i + 1..i + n)
+                    .any(|j| insns[j].label().is_some() || cfg.block_of(j) != cfg.block_of(i))
+                || insns[i + n - 1].flow() == Flow::Skip
+            {
+                continue;
+            }
+            let mut bindings = Bindings::new();
+            if rule
+                .pattern
+                .iter()
+                .zip(&insns[i..i + n])
+                .all(|(pat, insn)| pat.matches(insn, &mut bindings))
+                && rule.check(&bindings, liveness.live_out(i + n - 1))
+            {
+                let repl = rule.replacement(&bindings, insns[i].addr())?;
+                // A replacement with the same instructions would match again forever.
+                let same = repl.len() == n
+                    && repl
+                        .iter()
+                        .zip(&insns[i..i + n])
+                        .all(|(a, b)| a.name() == b.name() && a.ops() == b.ops());
+                if same {
+                    continue;
+                }
+                patches.push((i, n, repl));
+                counts[r] += 1;
+                i += n;
+                continue 'insns;
+            }
+        }
+        i += 1;
+    }
+
+    let count = patches.len();
+    for (i, n, repl) in patches {
+        part.insn_at_mut(i).set_patch(Some(InsnPatch::new(repl)));
+        for j in i + 1..i + n {
+            part.insn_at_mut(j).set_patch(Some(InsnPatch::empty()));
+        }
+    }
+    Ok(count)
+}
+
+pub async fn run(program: &mut Program, params: &Params) -> ah::Result<()> {
+    let file = params.path("file").display();
+    let text = std::fs::read_to_string(params.path("file"))
+        .with_context(|| format!("Read rule file '{file}'"))?;
+    let rules = parse_rules(&text).with_context(|| format!("Parse rule file '{file}'"))?;
+
+    let Some(text) = program.section_text_mut() else {
+        return Err(err!("Text section not found."));
+    };
+    let mut counts = vec![0; rules.len()];
+    for _ in 0..params.uint("passes") {
+        text.apply_patches()?;
+        let mut applied = 0;
+        for part in text.parts_mut() {
+            applied += apply_rules(part, &rules, &mut counts)
+                .with_context(|| format!("Part '{}'", part.name()))?;
+        }
+        if applied == 0 {
+            break;
+        }
+    }
+
+    println!("Rules from '{file}':");
+    for (rule, count) in rules.iter().zip(&counts) {
+        println!("  {}: {count} applied", rule.name);
+    }
+    println!("Rules: {} applied.", counts.iter().sum::<usize>());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{part_asm, program};
+
+    fn parse_err(text: &str) -> String {
+        format!("{:#}", parse_rules(text).unwrap_err())
+    }
+
+    /// Apply the rules to the part `main` until nothing changes.
+    fn rewrite(rules: &str, body: &str) -> Vec<String> {
+        let rules = parse_rules(rules).unwrap();
+        let mut program = program("atmega328p", &format!("main:\n{body}"));
+        let text = program.section_text_mut().unwrap();
+        let mut counts = vec![0; rules.len()];
+        loop {
+            text.apply_patches().unwrap();
+            let part = text.find_part_mut("main").unwrap();
+            if apply_rules(part, &rules, &mut counts).unwrap() == 0 {
+                break;
+            }
+        }
+        part_asm(&program, "main")
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse_err("ldi r24, 1\n"),
+            "Line 1: 'ldi r24, 1': Expected 'rule <name>'."
+        );
+        assert_eq!(
+            parse_err("rule x\n  foo r1\n=>\nend\n"),
+            "Line 2: 'foo r1': Unknown instruction 'foo'."
+        );
+        assert_eq!(
+            parse_err("rule x\n=>\nend\n"),
+            "Line 2: '=>': Empty pattern."
+        );
+        assert!(parse_err("rule x\n  mov %a, %b\n=>\n  mov %a, %c\nend\n").starts_with("Line 4:"));
+        assert!(parse_err("rule x\n  mov %a, %b\nif dead %c\n=>\nend\n").starts_with("Rule 'x'"));
+        assert_eq!(
+            parse_err("rule x\n  nop\n=>\n"),
+            "Rule 'x' is not terminated by 'end'."
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let rules = parse_rules(
+            "
+# Comment
+rule a ; trailing comment
+    ldi %r, $x
+    ldi %r, $y
+if $x != $y
+=>
+    ldi %r, $y
+end
+rule b
+    push %a
+    pop %a
+=>
+end
+",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].name, "a");
+        assert_eq!(rules[0].pattern.len(), 2);
+        assert_eq!(rules[0].constraints.len(), 1);
+        assert_eq!(rules[0].replacement.len(), 1);
+        assert!(rules[1].replacement.is_empty());
+    }
+
+    const LDI_LDI: &str = "
+rule ldi-ldi
+    ldi %a, $x
+    ldi %a, $y
+=>
+    ldi %a, $y
+end
+";
+
+    #[test]
+    fn test_match() {
+        assert_eq!(
+            rewrite(LDI_LDI, "    ldi r24, 0x01\n    ldi r24, 0x02\n    ret\n"),
+            ["ldi r24, 0x02", "ret"]
+        );
+        // Repeated metavariables must match the same register.
+        assert_eq!(
+            rewrite(LDI_LDI, "    ldi r24, 0x01\n    ldi r25, 0x02\n    ret\n"),
+            ["ldi r24, 0x01", "ldi r25, 0x02", "ret"]
+        );
+        // The pattern must not span a label.
+        assert_eq!(
+            rewrite(LDI_LDI, "    ldi r24, 0x01\n    l: ldi r24, 0x02\n    rjmp l\n"),
+            ["ldi r24, 0x01", "l: ldi r24, 0x02", "rjmp l"]
+        );
+    }
+
+    #[test]
+    fn test_constraints() {
+        let rules = "
+rule mov-dead
+    mov %a, %b
+if dead %a
+=>
+end
+rule and-ff
+    andi %a, $x
+if $x == 0xFF
+if dead sreg
+=>
+end
+";
+        // r18 is overwritten. Everything is live at the ret.
+        assert_eq!(
+            rewrite(
+                rules,
+                "    mov r18, r20\n    mov r24, r20\n    ldi r18, 0x01\n    ret\n"
+            ),
+            ["mov r24, r20", "ldi r18, 0x01", "ret"]
+        );
+        assert_eq!(
+            rewrite(rules, "    mov r24, r20\n    ret\n"),
+            ["mov r24, r20", "ret"]
+        );
+        // The flags are overwritten by the cp.
+        assert_eq!(
+            rewrite(rules, "    andi r24, 0xFF\n    cp r24, r25\n    ret\n"),
+            ["cp r24, r25", "ret"]
+        );
+        // The flags are used by the branch.
+        assert_eq!(
+            rewrite(rules, "    andi r24, 0xFF\n    breq l\n    l: ret\n"),
+            ["andi r24, 0xFF", "breq l", "l: ret"]
+        );
+    }
+
+    #[test]
+    fn test_skip_shadow() {
+        let rules = "rule x\n    nop\n=>\nend\n";
+        assert_eq!(
+            rewrite(rules, "    sbrc r24, 0\n    nop\n    nop\n    ret\n"),
+            ["sbrc r24, 0", "nop", "ret"]
+        );
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
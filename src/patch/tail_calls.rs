@@ -0,0 +1,89 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+use crate::{
+    cfg::Cfg,
+    program::{InsnPatch, Program},
+};
+use anyhow::{self as ah, format_err as err};
+
+pub async fn run(program: &mut Program) -> ah::Result<()> {
+    let Some(text) = program.section_text_mut() else {
+        return Err(err!("Text section not found."));
+    };
+    text.apply_patches()?;
+
+    let mut converted = 0;
+    for part in text.parts_mut() {
+        let cfg = Cfg::new(part);
+        for i in 0..part.insns().len().saturating_sub(1) {
+            let jmp = match part.insn_at(i).name() {
+                "rcall" => "rjmp",
+                "call" => "jmp",
+                _ => continue,
+            };
+            // The ret must only be reachable from the call.
+            // That is the case, if it is in the same basic block.
+            // A ret with a label, or the target of a skip or branch,
+            // starts a new block.
+            if part.insn_at(i + 1).name() != "ret"
+                || cfg.block_of(i) != cfg.block_of(i + 1)
+                || part.insn_at(i).ops()[0].label().is_none()
+            {
+                continue;
+            }
+            // call foo; ret  ->  jmp foo
+            // The callee returns directly to our caller.
+            let mut insn = part.insn_at(i).clone();
+            insn.set_name(jmp);
+            part.insn_at_mut(i).set_patch(Some(InsnPatch::new(vec![insn])));
+            part.insn_at_mut(i + 1).set_patch(Some(InsnPatch::empty()));
+            converted += 1;
+        }
+    }
+
+    println!("Tail calls: {converted} converted.");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{block_on, part_asm, program};
+
+    fn tail_calls(body: &str) -> Vec<String> {
+        let mut program = program("atmega328p", &format!("f:\n{body}g:\n    ret\n"));
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        part_asm(&program, "f")
+    }
+
+    #[test]
+    fn test_convert() {
+        assert_eq!(tail_calls("    rcall g\n    ret\n"), ["rjmp g"]);
+        assert_eq!(tail_calls("    call g\n    ret\n"), ["jmp g"]);
+    }
+
+    #[test]
+    fn test_keep() {
+        // The ret is reached from elsewhere.
+        assert_eq!(
+            tail_calls("    breq l\n    rcall g\n    l: ret\n"),
+            ["breq l", "rcall g", "l: ret"]
+        );
+        // The ret is the target of the skip.
+        assert_eq!(
+            tail_calls("    sbrc r24, 0\n    rcall g\n    ret\n"),
+            ["sbrc r24, 0", "rcall g", "ret"]
+        );
+        // Something happens after the call.
+        assert_eq!(
+            tail_calls("    rcall g\n    mov r24, r1\n    ret\n"),
+            ["rcall g", "mov r24, r1", "ret"]
+        );
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
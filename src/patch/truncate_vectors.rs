@@ -0,0 +1,193 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+use crate::{
+    callgraph::VECTORS,
+    devices::{device_vector_count, device_vector_words},
+    program::{InsnPatch, Program},
+};
+use anyhow::{self as ah, format_err as err};
+
+pub async fn run(program: &mut Program) -> ah::Result<()> {
+    let Some(device) = program.device() else {
+        return Err(err!("No device info."));
+    };
+    let Some(count) = device_vector_count(device) else {
+        return Err(err!(
+            "Number of interrupt vectors of device '{}' is unknown.",
+            device.device_name
+        ));
+    };
+    let vector_words = device_vector_words(device);
+    // Vector targets of interrupts that are not used by the program.
+    // bad-interrupt-exit redirects them from __bad_interrupt to its target.
+    let mut unused_targets = vec!["__bad_interrupt".to_string()];
+    unused_targets.extend(program.unused_vector_target().map(str::to_string));
+    // The trampolines are placed behind the vector table.
+    let trampolines_pinned = program
+        .pinned_addrs()
+        .iter()
+        .any(|pin| pin.label == "__trampolines_start");
+    let Some(text) = program.section_text_mut() else {
+        return Err(err!("Text section not found."));
+    };
+    text.apply_patches()?;
+
+    if trampolines_pinned
+        && text
+            .find_part("__trampolines_start")
+            .is_some_and(|p| !p.insns().is_empty())
+    {
+        println!("Truncate vectors: Not possible, the .trampolines must not move.");
+        return Ok(());
+    }
+
+    let Some(vectors) = text.find_part_mut(VECTORS) else {
+        return Err(err!("Interrupt vector table not found."));
+    };
+
+    // Group the instructions into the vector table entries.
+    let mut entries: Vec<Vec<usize>> = vec![];
+    let mut words: u32 = 0;
+    for (i, insn) in vectors.insns().iter().enumerate() {
+        if words.is_multiple_of(vector_words) {
+            entries.push(vec![]);
+        }
+        entries.last_mut().unwrap().push(i);
+        words += insn.size_words();
+    }
+    if words != count * vector_words {
+        return Err(err!(
+            "The interrupt vector table has {words} words, but {count} vectors \
+             of {vector_words} words are expected."
+        ));
+    }
+
+    // The highest vector that points to a handler.
+    let used = |entry: &[usize]| {
+        entry.iter().any(|&i| {
+            let insn = vectors.insn_at(i);
+            ["rjmp", "jmp"].contains(&insn.name())
+                && !insn.ops()[0]
+                    .label()
+                    .is_some_and(|l| unused_targets.iter().any(|t| t == l))
+        })
+    };
+    let last_used = entries.iter().rposition(|e| used(e)).unwrap_or(0);
+    let removed = entries.len() - 1 - last_used;
+    if removed == 0 {
+        println!("Truncate vectors: All vectors up to the last one are used.");
+        return Ok(());
+    }
+
+    for entry in &entries[last_used + 1..] {
+        for &i in entry {
+            vectors.insn_at_mut(i).set_patch(Some(InsnPatch::empty()));
+        }
+    }
+
+    eprintln!(
+        "WARNING: The interrupt vector table has been truncated after vector {last_used}.\n\
+         WARNING: Vectors {} to {} have been removed and are overwritten with program code.\n\
+         WARNING: Enabling any of these interrupts will crash the program.",
+        last_used + 1,
+        count - 1,
+    );
+    println!(
+        "Truncate vectors: {removed} vectors removed, {} bytes saved.",
+        removed as u32 * vector_words * 2
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{block_on, part_asm, program, program_with_data};
+
+    fn vectors(used: &[(usize, &str)]) -> Program {
+        let mut asm = String::from("__vectors:\n    jmp main\n");
+        for v in 1..26 {
+            let target = used
+                .iter()
+                .find(|(n, _)| *n == v)
+                .map(|(_, t)| *t)
+                .unwrap_or("foo");
+            asm.push_str(&format!("    jmp {target}\n"));
+        }
+        asm.push_str("isr:\n    reti\nmain:\n    rjmp main\nfoo:\n    rjmp foo\n");
+        let mut program = program("atmega328p", &asm);
+        program.set_unused_vector_target(Some("foo".to_string()));
+        program
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut program = vectors(&[(3, "isr")]);
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        assert_eq!(
+            part_asm(&program, "__vectors"),
+            ["jmp main", "jmp foo", "jmp foo", "jmp isr"]
+        );
+    }
+
+    #[test]
+    fn test_all_used() {
+        let mut program = vectors(&[(25, "isr")]);
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        assert_eq!(part_asm(&program, "__vectors").len(), 26);
+    }
+
+    #[test]
+    fn test_standalone() {
+        // Without bad-interrupt-exit the unused vectors jump to __bad_interrupt.
+        let mut program = vectors(&[(3, "isr")]);
+        program.set_unused_vector_target(None);
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        assert_eq!(part_asm(&program, "__vectors").len(), 26);
+
+        let asm = "__vectors:\n    jmp main\n    jmp __bad_interrupt\n    jmp isr\n"
+            .to_string()
+            + &"    jmp __bad_interrupt\n".repeat(23)
+            + "__bad_interrupt:\n    jmp __vectors\nisr:\n    reti\nmain:\n    rjmp main\n";
+        let mut program = crate::testutil::program("atmega328p", &asm);
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        assert_eq!(
+            part_asm(&program, "__vectors"),
+            ["jmp main", "jmp __bad_interrupt", "jmp isr"]
+        );
+    }
+
+    #[test]
+    fn test_trampolines() {
+        let asm = "__vectors:\n    jmp main\n".to_string()
+            + &"    jmp __bad_interrupt\n".repeat(56)
+            + "__trampolines_start:\n    jmp f\n__bad_interrupt:\n    jmp __vectors\n"
+            + "main:\n    ldi r30, 0x72\n    ldi r31, 0x00\n    eicall\n    rjmp main\n"
+            + "f:\n    ret\n";
+
+        // The trampolines move with the gs() immediate.
+        let mut program = program("atmega2560", &asm);
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        assert_eq!(part_asm(&program, "__vectors"), ["jmp main"]);
+        assert_eq!(
+            part_asm(&program, "main")[0],
+            "ldi r30, low(__trampolines_start)"
+        );
+
+        // The trampoline address is used as a plain number.
+        let mut program = program_with_data("atmega2560", &asm, &[0x72, 0x00]);
+        block_on(run(&mut program)).unwrap();
+        program.section_text_mut().unwrap().apply_patches().unwrap();
+        assert_eq!(part_asm(&program, "__vectors").len(), 57);
+    }
+}
+
+// vim: ts=4 sw=4 expandtab
@@ -2,8 +2,18 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 // Copyright (C) 2025 Michael Büsch <m@bues.ch>
 
-use crate::avr_deviceinfo::AvrDeviceInfoDesc;
-use anyhow::{self as ah, format_err as err};
+use crate::{
+    avr_deviceinfo::AvrDeviceInfoDesc,
+    devices::device_arch,
+    insninfo::{CoreFamily, Flow, InsnInfo, RegSet, insn_info, sreg},
+    opcodes::{check_insn, insn_size_words},
+    operand::{Dialect, Expr, ExprFunc, Operand},
+};
+use anyhow::{self as ah, Context as _, format_err as err};
+use std::fmt::Write as _;
+
+/// I/O address of the RAMPZ register.
+const RAMPZ_IO: u8 = 0x3b;
 
 #[derive(Clone, Debug)]
 pub struct InsnPatch {
@@ -27,14 +37,14 @@ impl InsnPatch {
 #[derive(Clone, Debug)]
 pub struct Insn {
     name: String,
-    ops: Vec<String>,
+    ops: Vec<Operand>,
     label: Option<String>,
-    addr: u16,
+    addr: u32,
     patch: Option<InsnPatch>,
 }
 
 impl Insn {
-    pub fn new(name: &str, ops: Vec<String>, label: Option<String>, addr: u16) -> Self {
+    pub fn new(name: &str, ops: Vec<Operand>, label: Option<String>, addr: u32) -> Self {
         Self {
             name: name.to_string(),
             label,
@@ -48,15 +58,19 @@ impl Insn {
         &self.name
     }
 
-    pub fn ops(&self) -> &[String] {
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    pub fn ops(&self) -> &[Operand] {
         &self.ops
     }
 
-    pub fn ops_mut(&mut self) -> &mut [String] {
+    pub fn ops_mut(&mut self) -> &mut [Operand] {
         &mut self.ops
     }
 
-    pub fn set_op(&mut self, index: usize, op: String) {
+    pub fn set_op(&mut self, index: usize, op: Operand) {
         self.ops[index] = op;
     }
 
@@ -68,10 +82,14 @@ impl Insn {
         self.label = label;
     }
 
-    pub fn addr(&self) -> u16 {
+    pub fn addr(&self) -> u32 {
         self.addr
     }
 
+    pub fn set_addr(&mut self, addr: u32) {
+        self.addr = addr;
+    }
+
     pub fn patch(&self) -> Option<&InsnPatch> {
         self.patch.as_ref()
     }
@@ -79,6 +97,87 @@ impl Insn {
     pub fn set_patch(&mut self, patch: Option<InsnPatch>) {
         self.patch = patch;
     }
+
+    /// Static properties of the instruction.
+    /// `None` for data words.
+    #[allow(unused)]
+    pub fn info(&self) -> Option<&'static InsnInfo> {
+        insn_info(self.name(), self.ops())
+    }
+
+    /// Size of the instruction in flash words.
+    #[allow(unused)]
+    pub fn size_words(&self) -> u32 {
+        insn_size_words(self.name()).unwrap_or(1)
+    }
+
+    /// Control flow kind of the instruction.
+    #[allow(unused)]
+    pub fn flow(&self) -> Flow {
+        self.info().map(|i| i.flow()).unwrap_or(Flow::Next)
+    }
+
+    /// Minimum and maximum number of cycles on the given core.
+    #[allow(unused)]
+    pub fn cycles(&self, family: CoreFamily) -> (u8, u8) {
+        self.info().map(|i| i.cycles(family)).unwrap_or((0, 0))
+    }
+
+    /// Registers written by the instruction, including implicit ones.
+    #[allow(unused)]
+    pub fn reg_defs(&self) -> RegSet {
+        self.info()
+            .map(|i| i.reg_defs(self.ops()))
+            .unwrap_or_default()
+    }
+
+    /// Registers read by the instruction, including implicit ones.
+    #[allow(unused)]
+    pub fn reg_uses(&self) -> RegSet {
+        self.info()
+            .map(|i| i.reg_uses(self.ops()))
+            .unwrap_or_default()
+    }
+
+    /// SREG flags written by the instruction.
+    #[allow(unused)]
+    pub fn sreg_defs(&self) -> u8 {
+        if self.name() == "out" && self.ops()[0].io() == Some(sreg::IO) {
+            return sreg::ALL;
+        }
+        self.info().map(|i| i.sreg_defs()).unwrap_or(0)
+    }
+
+    /// SREG flags read by the instruction.
+    #[allow(unused)]
+    pub fn sreg_uses(&self) -> u8 {
+        if self.name() == "in" && self.ops()[1].io() == Some(sreg::IO) {
+            return sreg::ALL;
+        }
+        self.info().map(|i| i.sreg_uses()).unwrap_or(0)
+    }
+
+    /// Check that the instruction exists and that its operands are valid.
+    pub fn check(&self) -> ah::Result<()> {
+        check_insn(self.name(), self.ops())
+    }
+
+    /// Format the instruction in the given assembler dialect.
+    pub fn to_asm(&self, dialect: Dialect) -> String {
+        let mut s = String::new();
+        if let Some(label) = &self.label {
+            s.push_str(&format!("{label}: "));
+        }
+        match (dialect, self.name()) {
+            (Dialect::Avra, ".word") => s.push_str(".dw"),
+            (_, name) => s.push_str(name),
+        }
+        for (i, op) in self.ops().iter().enumerate() {
+            let sep = if i == 0 { " " } else { ", " };
+            s.push_str(&format!("{sep}{}", op.to_asm(dialect)));
+        }
+        s
+    }
 }
 
 impl std::fmt::Display for Insn {
@@ -119,6 +218,8 @@ pub struct Part {
     demangled: String,
     insns: Vec<Insn>,
     patch: Option<Box<PartPatch>>,
+    /// The part never returns to its caller.
+    noreturn: bool,
 }
 
 impl Part {
@@ -128,11 +229,14 @@ impl Part {
             demangled: demangled.to_string(),
             insns: vec![],
             patch: None,
+            noreturn: false,
         }
     }
 
     pub fn clone_empty(&self) -> Part {
-        Self::new(self.name(), self.demangled())
+        let mut part = Self::new(self.name(), self.demangled());
+        part.noreturn = self.noreturn;
+        part
     }
 
     pub fn name(&self) -> &str {
@@ -143,6 +247,14 @@ impl Part {
         &self.demangled
     }
 
+    pub fn is_noreturn(&self) -> bool {
+        self.noreturn
+    }
+
+    pub fn set_noreturn(&mut self, noreturn: bool) {
+        self.noreturn = noreturn;
+    }
+
     pub fn add_insn(&mut self, insn: Insn) {
         self.insns.push(insn);
     }
@@ -174,6 +286,59 @@ impl Part {
     pub fn set_patch_delete_part(&mut self) {
         self.set_patch(Some(PartPatch::new(self.clone_empty())));
     }
+
+    /// The instruction at `index` is in the shadow of a skip instruction.
+    pub fn in_skip_shadow(&self, index: usize) -> bool {
+        index > 0 && self.insns[index - 1].flow() == Flow::Skip
+    }
+
+    /// Check that no instruction patch changes the length of a skip instruction shadow.
+    /// The skip would skip something else than the original instruction.
+    pub fn check_skip_shadows(&self) -> ah::Result<()> {
+        for (i, insn) in self.insns.iter().enumerate() {
+            let Some(patch) = insn.patch() else {
+                continue;
+            };
+            if !self.in_skip_shadow(i) {
+                continue;
+            }
+            // The skip instruction itself may be replaced by something else.
+            let skip = &self.insns[i - 1];
+            let still_skip = skip
+                .patch()
+                .is_none_or(|p| p.insns().last().is_some_and(|i| i.flow() == Flow::Skip));
+            let same_size = match patch.insns() {
+                [new] => new.size_words() == insn.size_words(),
+                _ => false,
+            };
+            if still_skip && !same_size {
+                return Err(err!(
+                    "{}: The patch of '{insn}' at 0x{:X} changes the length \
+                     of the shadow of the skip instruction '{skip}'.",
+                    self.name(),
+                    insn.addr()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the part with the part patch applied.
+    /// A deleted part has no instructions.
+    pub fn final_part(&self) -> &Part {
+        match self.patch() {
+            Some(patch) => patch.part(),
+            None => self,
+        }
+    }
+
+    /// Get the instructions with all instruction patches applied.
+    pub fn final_insns(&self) -> impl Iterator<Item = &Insn> {
+        self.insns.iter().flat_map(|insn| match insn.patch() {
+            Some(patch) => patch.insns().iter(),
+            None => std::slice::from_ref(insn).iter(),
+        })
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -219,7 +384,6 @@ impl CodeSection {
         self.parts.push(part);
     }
 
-    #[allow(unused)]
     pub fn find_part(&self, name: &str) -> Option<&Part> {
         self.parts.iter().find(|p| p.name() == name)
     }
@@ -227,6 +391,88 @@ impl CodeSection {
     pub fn find_part_mut(&mut self, name: &str) -> Option<&mut Part> {
         self.parts.iter_mut().find(|p| p.name() == name)
     }
+
+    /// Get the parts with all part patches applied.
+    /// Deleted parts are skipped.
+    pub fn final_parts(&self) -> impl Iterator<Item = &Part> {
+        self.parts.iter().filter_map(|part| match part.patch() {
+            Some(patch) if patch.part().insns().is_empty() => None,
+            Some(patch) => Some(patch.part()),
+            None => Some(part),
+        })
+    }
+
+    /// Replace all parts and instructions by their patched versions,
+    /// so that the section contains no patches anymore.
+    /// The label of a removed instruction moves to the next instruction.
+    pub fn apply_patches(&mut self) -> ah::Result<()> {
+        for part in &self.parts {
+            part.check_skip_shadows()?;
+        }
+
+        let mut renames = vec![];
+        let mut pending: Option<String> = None;
+        let mut parts = vec![];
+
+        for part in std::mem::take(&mut self.parts) {
+            let part = match part.patch {
+                Some(patch) if patch.part.insns().is_empty() => continue,
+                Some(patch) => patch.part,
+                None => part,
+            };
+            if let Some(label) = pending.take() {
+                renames.push((label, part.name().to_string()));
+            }
+            let mut new_part = part.clone_empty();
+            for mut insn in part.insns {
+                let (label, insns) = match insn.patch.take() {
+                    Some(patch) => (insn.label.take(), patch.insns),
+                    None => (None, vec![insn]),
+                };
+                if let Some(label) = label {
+                    match &pending {
+                        Some(p) => renames.push((label, p.clone())),
+                        None => pending = Some(label),
+                    }
+                }
+                for mut insn in insns {
+                    if let Some(label) = pending.take() {
+                        match insn.label() {
+                            None => insn.set_label(Some(label)),
+                            Some(l) if l == label => (),
+                            Some(l) => renames.push((label, l.to_string())),
+                        }
+                    }
+                    new_part.add_insn(insn);
+                }
+            }
+            parts.push(new_part);
+        }
+        if let Some(label) = pending {
+            renames.push((label, "____section_data__".to_string()));
+        }
+        self.parts = parts;
+
+        for (old, new) in renames {
+            self.rename_label(&old, &new);
+        }
+        Ok(())
+    }
+
+    /// Replace all references to the label `old` by references to `new`.
+    pub fn rename_label(&mut self, old: &str, new: &str) {
+        for part in &mut self.parts {
+            for insn in &mut part.insns {
+                for op in insn.ops_mut() {
+                    match op {
+                        Operand::Label(label) if label == old => *label = new.to_string(),
+                        Operand::Expr(expr) if expr.label == old => expr.label = new.to_string(),
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -250,11 +496,24 @@ impl DataSection {
     }
 }
 
+/// A label whose flash address is used by the program as a plain number.
+/// The label must stay at its original address.
+#[derive(Clone, Debug)]
+pub struct PinnedAddr {
+    pub label: String,
+    /// Original flash word address.
+    pub addr: u32,
+    /// Kind of the reference that pins the address.
+    pub reason: &'static str,
+}
+
 #[derive(Clone, Debug)]
 pub struct Program {
     text: Option<CodeSection>,
     data: Option<DataSection>,
     device: Option<AvrDeviceInfoDesc>,
+    pinned: Vec<PinnedAddr>,
+    unused_vector_target: Option<String>,
 }
 
 impl Program {
@@ -263,6 +522,8 @@ impl Program {
             text: None,
             data: None,
             device: None,
+            pinned: vec![],
+            unused_vector_target: None,
         }
     }
 
@@ -299,6 +560,32 @@ impl Program {
         self.device.as_ref()
     }
 
+    pub fn set_pinned_addrs(&mut self, pinned: Vec<PinnedAddr>) {
+        self.pinned = pinned;
+    }
+
+    pub fn pinned_addrs(&self) -> &[PinnedAddr] {
+        &self.pinned
+    }
+
+    /// Set the function that all unused interrupt vectors jump to.
+    pub fn set_unused_vector_target(&mut self, target: Option<String>) {
+        self.unused_vector_target = target;
+    }
+
+    pub fn unused_vector_target(&self) -> Option<&str> {
+        self.unused_vector_target.as_deref()
+    }
+
+    /// Core family of the device, for instruction timing.
+    #[allow(unused)]
+    pub fn core_family(&self) -> CoreFamily {
+        self.device
+            .as_ref()
+            .map(|d| CoreFamily::from_arch(device_arch(d)))
+            .unwrap_or(CoreFamily::Avre)
+    }
+
     pub fn to_asm(&self) -> ah::Result<String> {
         if let Some(device) = self.device.as_ref() {
             Ok(format!(".device {}\n\n{}", device.device_name, self))
@@ -307,6 +594,54 @@ impl Program {
         }
     }
 
+    /// Check all instructions of the program with all patches applied
+    /// and the patches of skip instruction shadows.
+    pub fn check(&self) -> ah::Result<()> {
+        if let Some(text) = self.section_text() {
+            for part in text.parts() {
+                part.check_skip_shadows()?;
+            }
+            for part in text.final_parts() {
+                for insn in part.final_insns() {
+                    insn.check().with_context(|| {
+                        format!("{}: Invalid instruction '{insn}'", part.name())
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert the program to GNU assembler (avr-as) syntax.
+    pub fn to_gnu_asm(&self) -> ah::Result<String> {
+        let mut out = String::new();
+        if let Some(sect) = self.section_text() {
+            writeln!(out, "    .section .text")?;
+            // Entry point of the linker.
+            writeln!(out, "    .global ____section_text__")?;
+            writeln!(out, "____section_text__:")?;
+            for part in sect.final_parts() {
+                if part.name() == part.demangled() {
+                    writeln!(out, "{}:", part.name())?;
+                } else {
+                    writeln!(out, "{}: ; {}", part.name(), part.demangled())?;
+                }
+                for insn in part.final_insns() {
+                    writeln!(out, "    {}", insn.to_asm(Dialect::Gnu))?;
+                }
+            }
+        }
+        if let Some(sect) = self.section_data() {
+            writeln!(out)?;
+            writeln!(out, "____section_data__:")?;
+            for chunk in sect.data().chunks(8) {
+                let bytes: Vec<String> = chunk.iter().map(|b| format!("0x{b:02X}")).collect();
+                writeln!(out, "    .byte {}", bytes.join(", "))?;
+            }
+        }
+        Ok(out)
+    }
+
     pub fn fixup_data_load_addr(&mut self) -> ah::Result<()> {
         let Some(text) = self.section_text_mut() else {
             return Err(err!("No .text section."));
@@ -317,19 +652,47 @@ impl Program {
             ));
         };
 
+        // On devices with more than 64 KiB of flash the copy loop uses elpm
+        // and the third address byte is loaded into RAMPZ.
+        let rampz_reg = if fcopy.insns().iter().any(|insn| insn.name() == "elpm") {
+            let Some(out) = fcopy.insns().iter().find(|insn| {
+                insn.name() == "out"
+                    && insn.ops().len() == 2
+                    && insn.ops()[0].io() == Some(RAMPZ_IO)
+            }) else {
+                return Err(err!(
+                    "__do_copy_data: elpm is used, but RAMPZ is not written."
+                ));
+            };
+            out.ops()[1].reg()
+        } else {
+            None
+        };
+
+        let data_addr = |func| Operand::Expr(Expr::new(func, "____section_data__", false));
+
         let mut zl = false;
         let mut zh = false;
+        let mut zb3 = rampz_reg.is_none();
         for insn in fcopy.insns_mut() {
-            if insn.name() == "ldi" && insn.ops().len() == 2 && insn.ops()[0] == "r30" {
-                insn.ops_mut()[1] = "low(____section_data__ * 2)".to_string();
+            let ldi_reg = if insn.name() == "ldi" && insn.ops().len() == 2 {
+                insn.ops()[0].reg()
+            } else {
+                None
+            };
+            if ldi_reg == Some(30) {
+                insn.ops_mut()[1] = data_addr(ExprFunc::Lo8);
                 zl = true;
-            } else if insn.name() == "ldi" && insn.ops().len() == 2 && insn.ops()[0] == "r31" {
-                insn.ops_mut()[1] = "high(____section_data__ * 2)".to_string();
+            } else if ldi_reg == Some(31) {
+                insn.ops_mut()[1] = data_addr(ExprFunc::Hi8);
                 zh = true;
-            } else if zl || zh {
+            } else if !zb3 && ldi_reg.is_some() && ldi_reg == rampz_reg {
+                insn.ops_mut()[1] = data_addr(ExprFunc::Hh8);
+                zb3 = true;
+            } else if (zl || zh) && rampz_reg.is_none() {
                 break;
             }
-            if zl && zh {
+            if zl && zh && zb3 {
                 return Ok(());
             }
         }
@@ -341,7 +704,7 @@ impl Program {
 impl std::fmt::Display for Program {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         fn write_insn(f: &mut std::fmt::Formatter<'_>, insn: &Insn) -> Result<(), std::fmt::Error> {
-            writeln!(f, "    {insn}")
+            writeln!(f, "    {}", insn.to_asm(Dialect::Avra))
         }
 
         fn write_part(f: &mut std::fmt::Formatter<'_>, part: &Part) -> Result<(), std::fmt::Error> {
@@ -350,14 +713,8 @@ impl std::fmt::Display for Program {
             } else {
                 writeln!(f, "{}: ; {}", part.name(), part.demangled())?;
             }
-            for insn in part.insns() {
-                if let Some(patch) = insn.patch() {
-                    for pinsn in patch.insns() {
-                        write_insn(f, pinsn)?;
-                    }
-                } else {
-                    write_insn(f, insn)?;
-                }
+            for insn in part.final_insns() {
+                write_insn(f, insn)?;
             }
             Ok(())
         }
@@ -365,14 +722,8 @@ impl std::fmt::Display for Program {
         if let Some(sect) = self.section_text() {
             writeln!(f, ".cseg ;flash")?;
             writeln!(f, "____section_text__:")?;
-            for part in sect.parts() {
-                if let Some(patch) = part.patch() {
-                    if !patch.part().insns().is_empty() {
-                        write_part(f, patch.part())?;
-                    }
-                } else {
-                    write_part(f, part)?;
-                }
+            for part in sect.final_parts() {
+                write_part(f, part)?;
             }
         }
         if let Some(sect) = self.section_data() {
@@ -0,0 +1,111 @@
+// -*- coding: utf-8 -*-
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright (C) 2025 Michael Büsch <m@bues.ch>
+
+//! Helpers for the unit tests.
+
+use crate::{
+    avr_deviceinfo::AvrDeviceInfoDesc,
+    dasm::relocate_code_addrs,
+    layout::pin_addrs,
+    opcodes::{insn_size_words, parse_insn_ops},
+    operand::is_label,
+    program::{CodeSection, DataSection, Insn, Part, Program},
+};
+
+/// Device info of some devices.
+pub fn device(name: &str) -> AvrDeviceInfoDesc {
+    let (flash_size, sram_start, sram_size, eeprom_size) = match name {
+        "attiny85" => (8 * 1024, 0x60, 512, 512),
+        "atmega8" => (8 * 1024, 0x60, 1024, 512),
+        "atmega328p" => (32 * 1024, 0x100, 2048, 1024),
+        "atmega1284p" => (128 * 1024, 0x100, 16 * 1024, 4096),
+        "atmega2560" => (256 * 1024, 0x200, 8 * 1024, 4096),
+        _ => panic!("Unknown test device '{name}'."),
+    };
+    AvrDeviceInfoDesc {
+        flash_start: 0,
+        flash_size,
+        sram_start,
+        sram_size,
+        eeprom_start: 0,
+        eeprom_size,
+        device_name: name.to_string(),
+    }
+}
+
+/// Build a program from assembly code, as the disassembler would.
+///
+/// Lines without indentation that end with a colon start a new part.
+/// Instructions may have a `label:` prefix.
+pub fn program(device_name: &str, asm: &str) -> Program {
+    build(device_name, asm, None)
+}
+
+/// Build a program with a .data section.
+pub fn program_with_data(device_name: &str, asm: &str, data: &[u8]) -> Program {
+    build(device_name, asm, Some(data))
+}
+
+fn build(device_name: &str, asm: &str, data: Option<&[u8]>) -> Program {
+    let mut text = CodeSection::new(".text");
+    let mut addr = 0;
+    for line in asm.lines() {
+        let line = line.split(';').next().unwrap().trim_end();
+        if line.trim().is_empty() {
+            continue;
+        }
+        if !line.starts_with(char::is_whitespace) {
+            let name = line.strip_suffix(':').expect("Part name");
+            text.add_part(Part::new(name, name));
+            continue;
+        }
+        let mut line = line.trim();
+        let mut label = None;
+        if let Some((l, rest)) = line.split_once(": ")
+            && is_label(l)
+        {
+            label = Some(l.to_string());
+            line = rest.trim();
+        }
+        let (name, ops) = line.split_once(' ').unwrap_or((line, ""));
+        let ops: Vec<&str> = ops
+            .split(',')
+            .map(str::trim)
+            .filter(|o| !o.is_empty())
+            .collect();
+        let ops = parse_insn_ops(name, &ops).unwrap();
+        let part = text.cur_part_mut().expect("Instruction outside of a part");
+        part.add_insn(Insn::new(name, ops, label, addr));
+        addr += insn_size_words(name).unwrap() * 2;
+    }
+
+    let mut program = Program::new();
+    program.set_section_text(Some(text));
+    program.set_device(Some(device(device_name)));
+    program.set_section_data(data.map(|d| DataSection::new(".data".to_string(), d.to_vec())));
+    let mut rel_target = 0;
+    relocate_code_addrs(&mut program, &mut rel_target).unwrap();
+    pin_addrs(&mut program);
+    program
+}
+
+/// The instructions of a part, with all patches applied.
+/// Returns an empty list for a deleted part.
+pub fn part_asm(program: &Program, name: &str) -> Vec<String> {
+    let text = program.section_text().unwrap();
+    text.final_parts()
+        .find(|p| p.name() == name)
+        .map(|p| p.final_insns().map(|i| i.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// Run a patch step to completion.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+// vim: ts=4 sw=4 expandtab